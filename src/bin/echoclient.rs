@@ -0,0 +1,43 @@
+extern crate clap;
+#[macro_use] extern crate log;
+extern crate env_logger;
+
+extern crate cosupport;
+
+use std::net::Shutdown;
+use std::io::{Read, Write};
+
+use clap::{Arg, App};
+
+use cosupport::scheduler::Scheduler;
+use cosupport::net::tcp::TcpStream;
+
+fn main() {
+    env_logger::init().unwrap();
+
+    let matches = App::new("tcp-echo-client")
+            .version(env!("CARGO_PKG_VERSION"))
+            .author("Y. T. Chung <zonyitoo@gmail.com>")
+            .arg(Arg::with_name("CONNECT").short("c").long("connect").takes_value(true).required(true)
+                    .help("Connect to this address"))
+            .arg(Arg::with_name("MESSAGE").short("m").long("message").takes_value(true).required(true)
+                    .help("Message to be echoed"))
+            .get_matches();
+
+    let addr = matches.value_of("CONNECT").unwrap().to_owned();
+    let message = matches.value_of("MESSAGE").unwrap().to_owned();
+
+    Scheduler::run(move|| {
+        let mut stream = TcpStream::connect_happy_eyeballs(&addr[..]).unwrap();
+        info!("Connected to {:?}", stream.peer_addr().unwrap());
+
+        stream.write_all(message.as_bytes()).unwrap();
+
+        // Half-close, the server still sends back everything it has read
+        stream.shutdown(Shutdown::Write).unwrap();
+
+        let mut echoed = String::new();
+        stream.read_to_string(&mut echoed).unwrap();
+        println!("{}", echoed);
+    }, 1);
+}
@@ -6,11 +6,35 @@ extern crate mio;
 extern crate cosupport;
 
 use std::net::SocketAddr;
+use std::io::{Read, Write};
 
 use clap::{Arg, App};
 
 use cosupport::scheduler::Scheduler;
-use cosupport::net::tcp::TcpSocket;
+use cosupport::net::tcp::{TcpSocket, TcpListener, TcpStream};
+
+fn echo(mut stream: TcpStream) {
+    let mut buf = [0; 10240];
+
+    loop {
+        debug!("Trying to Read...");
+        match stream.read(&mut buf) {
+            Ok(0) => {
+                debug!("EOF received, going to close");
+                break;
+            },
+            Ok(len) => {
+                info!("Read {} bytes, echo back!", len);
+                stream.write_all(&buf[0..len]).unwrap();
+            },
+            Err(err) => {
+                panic!("Error occurs: {:?}", err);
+            }
+        }
+    }
+
+    info!("{:?} closed", stream.peer_addr().unwrap());
+}
 
 fn main() {
     env_logger::init().unwrap();
@@ -22,11 +46,23 @@ fn main() {
                     .help("Listening on this address"))
             .arg(Arg::with_name("THREADS").short("t").long("threads").takes_value(true)
                     .help("Number of threads"))
+            .arg(Arg::with_name("SHARDED").short("s").long("sharded")
+                    .help("Accept on one SO_REUSEPORT listener per thread (Linux only)"))
             .get_matches();
 
     let bind_addr = matches.value_of("BIND").unwrap().to_owned();
+    let sharded = matches.is_present("SHARDED");
 
     Scheduler::run(move|| {
+        if sharded {
+            info!("Listening on {} with {} shards", bind_addr, Scheduler::threads());
+            TcpListener::bind_sharded(&bind_addr[..], |stream| {
+                info!("Accept connection: {:?}", stream.peer_addr().unwrap());
+                echo(stream);
+            }).unwrap();
+            return;
+        }
+
         let addr = bind_addr.parse().unwrap();
         let server = match &addr {
             &SocketAddr::V4(..) => TcpSocket::v4(),
@@ -38,33 +74,10 @@ fn main() {
         info!("Listening on {:?}", server.local_addr().unwrap());
 
         loop {
-            use std::io::{Read, Write};
-
-            let mut stream = server.accept().unwrap();
+            let stream = server.accept().unwrap();
             info!("Accept connection: {:?}", stream.peer_addr().unwrap());
 
-            Scheduler::spawn(move|| {
-                let mut buf = [0; 10240];
-
-                loop {
-                    debug!("Trying to Read...");
-                    match stream.read(&mut buf) {
-                        Ok(0) => {
-                            debug!("EOF received, going to close");
-                            break;
-                        },
-                        Ok(len) => {
-                            info!("Read {} bytes, echo back!", len);
-                            stream.write_all(&buf[0..len]).unwrap();
-                        },
-                        Err(err) => {
-                            panic!("Error occurs: {:?}", err);
-                        }
-                    }
-                }
-
-                info!("{:?} closed", stream.peer_addr().unwrap());
-            });
+            Scheduler::spawn(move|| echo(stream));
         }
     }, matches.value_of("THREADS").unwrap_or("1").parse().unwrap());
 }
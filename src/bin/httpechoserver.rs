@@ -1,130 +1,29 @@
 extern crate clap;
 #[macro_use] extern crate log;
 extern crate env_logger;
-extern crate mio;
 extern crate hyper;
 
 extern crate cosupport;
 extern crate openssl;
 
-use std::net::{ToSocketAddrs, SocketAddr, Shutdown};
+use std::collections::HashMap;
 use std::path::Path;
-use std::io::{self, Read, Write, BufWriter};
 use std::sync::Arc;
-use std::fmt;
+use std::io;
 
 use clap::{Arg, App};
 
-use hyper::http;
-use hyper::buffer::BufReader;
-use hyper::server::{Request, Response, Handler};
-use hyper::header::{Connection, Headers, Expect};
-use hyper::version::HttpVersion;
-use hyper::net::{NetworkListener, NetworkStream};
-use hyper::status::StatusCode;
-use hyper::uri::RequestUri::AbsolutePath;
-use hyper::error::Error;
-use hyper::method::Method;
+use hyper::server::{Request, Response};
 
-use openssl::ssl::{SslStream, SslContext, SSL_VERIFY_NONE};
-use openssl::ssl::SslMethod::Sslv23;
-use openssl::ssl::error::StreamError as SslIoError;
-use openssl::x509::X509FileType;
+use openssl::ssl::SslContext;
 
-use cosupport::scheduler::Scheduler;
-use cosupport::net::tcp::{TcpStream, TcpListener};
+use cosupport::scheduler::SchedulerConfig;
+use cosupport::http::{Server, ServerStats, Limits, Router, Params, https_context, DEFAULT_HEADER_TIMEOUT_MS, DEFAULT_IDLE_TIMEOUT_MS};
+use cosupport::net::tls::{self, TlsAcceptor, ReloadableAcceptor};
+use cosupport::signal::{Signals, SIGHUP, SIGUSR1};
 
-struct Worker<'a, H: Handler + 'static>(&'a H);
-
-impl<'a, H: Handler + 'static> Worker<'a, H> {
-
-    fn handle_connection<S>(&self, mut stream: &mut S) where S: NetworkStream + Clone {
-        debug!("Incoming stream");
-        let addr = match stream.peer_addr() {
-            Ok(addr) => addr,
-            Err(e) => {
-                error!("Peer Name error: {:?}", e);
-                return;
-            }
-        };
-
-        // FIXME: Use Type ascription
-        let stream_clone: &mut NetworkStream = &mut stream.clone();
-        let rdr = BufReader::new(stream_clone);
-        let wrt = BufWriter::new(stream);
-
-        self.keep_alive_loop(rdr, wrt, addr);
-        debug!("keep_alive loop ending for {}", addr);
-    }
-
-    fn keep_alive_loop<W: Write>(&self, mut rdr: BufReader<&mut NetworkStream>, mut wrt: W, addr: SocketAddr) {
-        let mut keep_alive = true;
-        while keep_alive {
-            let req = match Request::new(&mut rdr, addr) {
-                Ok(req) => req,
-                Err(Error::Io(ref e)) if e.kind() == io::ErrorKind::ConnectionAborted => {
-                    trace!("tcp closed, cancelling keep-alive loop");
-                    break;
-                }
-                Err(Error::Io(e)) => {
-                    debug!("ioerror in keepalive loop = {:?}", e);
-                    break;
-                }
-                Err(e) => {
-                    //TODO: send a 400 response
-                    error!("request error = {:?}", e);
-                    break;
-                }
-            };
-
-
-            if !self.handle_expect(&req, &mut wrt) {
-                break;
-            }
-
-            keep_alive = http::should_keep_alive(req.version, &req.headers);
-            let version = req.version;
-            let mut res_headers = Headers::new();
-            if !keep_alive {
-                res_headers.set(Connection::close());
-            }
-            {
-                let mut res = Response::new(&mut wrt, &mut res_headers);
-                res.version = version;
-                self.0.handle(req, res);
-            }
-
-            // if the request was keep-alive, we need to check that the server agrees
-            // if it wasn't, then the server cannot force it to be true anyways
-            if keep_alive {
-                keep_alive = http::should_keep_alive(version, &res_headers);
-            }
-
-            debug!("keep_alive = {:?} for {}", keep_alive, addr);
-        }
-
-    }
-
-    fn handle_expect<W: Write>(&self, req: &Request, wrt: &mut W) -> bool {
-         if req.version == HttpVersion::Http11 && req.headers.get() == Some(&Expect::Continue) {
-            let status = self.0.check_continue((&req.method, &req.uri, &req.headers));
-            match write!(wrt, "{} {}\r\n\r\n", HttpVersion::Http11, status) {
-                Ok(..) => (),
-                Err(e) => {
-                    error!("error writing 100-continue: {:?}", e);
-                    return false;
-                }
-            }
-
-            if status != StatusCode::Continue {
-                debug!("non-100 status ({}) for Expect 100 request", status);
-                return false;
-            }
-        }
-
-        true
-    }
-}
+/// Time a slow client gets before its body transfer rate is checked
+const MIN_BODY_RATE_GRACE_MS: u64 = 5_000;
 
 macro_rules! try_return(
     ($e:expr) => {{
@@ -135,197 +34,104 @@ macro_rules! try_return(
     }}
 );
 
-fn echo(mut req: Request, mut res: Response) {
-    match req.uri {
-        AbsolutePath(ref path) => match (&req.method, &path[..]) {
-            (&Method::Get, "/") | (&Method::Get, "/echo") => {
-                try_return!(res.send(b"Try POST /echo"));
-                return;
-            },
-            (&Method::Post, "/echo") => (), // fall through, fighting mutable borrows
-            _ => {
-                *res.status_mut() = hyper::NotFound;
-                return;
-            }
-        },
-        _ => {
-            return;
-        }
-    };
+fn usage(_: Request, res: Response, _: &Params) {
+    try_return!(res.send(b"Try POST /echo"));
+}
 
+fn echo(mut req: Request, res: Response, _: &Params) {
     let mut res = try_return!(res.start());
     try_return!(io::copy(&mut req, &mut res));
 }
 
-/// A `NetworkListener` for `HttpStream`s.
-pub enum HttpListener {
-    /// Http variant.
-    Http(TcpListener),
-    /// Https variant. The two paths point to the certificate and key PEM files, in that order.
-    Https(TcpListener, Arc<SslContext>)
+/// Echoes the rest of the path after `/echo/`
+fn echo_path(_: Request, res: Response, params: &Params) {
+    try_return!(res.send(params.get("text").unwrap_or("").as_bytes()));
 }
 
-impl Clone for HttpListener {
-    fn clone(&self) -> HttpListener {
-        match *self {
-            HttpListener::Http(ref tcp) => HttpListener::Http(tcp.try_clone().unwrap()),
-            HttpListener::Https(ref tcp, ref ssl) => HttpListener::Https(tcp.try_clone().unwrap(), ssl.clone()),
-        }
-    }
-}
-
-impl HttpListener {
-
-    /// Start listening to an address over HTTP.
-    pub fn http<To: ToSocketAddrs>(addr: To) -> hyper::Result<HttpListener> {
-        Ok(HttpListener::Http(try!(TcpListener::bind(addr))))
-    }
-
-    /// Start listening to an address over HTTPS.
-    pub fn https<To: ToSocketAddrs>(addr: To, cert: &Path, key: &Path) -> hyper::Result<HttpListener> {
-        let mut ssl_context = try!(SslContext::new(Sslv23));
-        try!(ssl_context.set_cipher_list("DEFAULT"));
-        try!(ssl_context.set_certificate_file(cert, X509FileType::PEM));
-        try!(ssl_context.set_private_key_file(key, X509FileType::PEM));
-        ssl_context.set_verify(SSL_VERIFY_NONE, None);
-        HttpListener::https_with_context(addr, ssl_context)
-    }
-
-    /// Start listening to an address of HTTPS using the given SslContext
-    pub fn https_with_context<To: ToSocketAddrs>(addr: To, ssl_context: SslContext) -> hyper::Result<HttpListener> {
-        Ok(HttpListener::Https(try!(TcpListener::bind(addr)), Arc::new(ssl_context)))
-    }
+/// TLS settings from the command line, kept to rebuild the acceptor when the files change
+#[derive(Clone)]
+struct TlsConfig {
+    cert: String,
+    key: String,
+    /// `HOST:CERT:KEY` entries
+    sni: Vec<String>,
+    /// CA bundle for client certificates, and whether a certificate is required
+    client_ca: Option<(String, bool)>,
 }
 
-impl NetworkListener for HttpListener {
-    type Stream = HttpStream;
-
-    #[inline]
-    fn accept(&mut self) -> hyper::Result<HttpStream> {
-        match *self {
-            HttpListener::Http(ref mut tcp) => Ok(HttpStream::Http(CloneTcpStream(try!(tcp.accept())))),
-            HttpListener::Https(ref mut tcp, ref ssl_context) => {
-                let stream = CloneTcpStream(try!(tcp.accept()));
-                match SslStream::new_server(&**ssl_context, stream) {
-                    Ok(ssl_stream) => Ok(HttpStream::Https(ssl_stream)),
-                    Err(SslIoError(e)) => {
-                        Err(io::Error::new(io::ErrorKind::ConnectionAborted, e).into())
-                    },
-                    Err(e) => Err(e.into())
-                }
+impl TlsConfig {
+    /// Builds the acceptor by reading all certificate and key files
+    fn acceptor(&self) -> io::Result<TlsAcceptor> {
+        let mut default = try!(https_context(Path::new(&self.cert), Path::new(&self.key)));
+        try!(self.verify_clients(&mut default));
+
+        let mut hosts = HashMap::new();
+        for entry in self.sni.iter() {
+            let parts: Vec<&str> = entry.splitn(3, ':').collect();
+            if parts.len() != 3 {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "--sni expects HOST:CERT:KEY"));
             }
+            let mut ctx = try!(https_context(Path::new(parts[1]), Path::new(parts[2])));
+            try!(self.verify_clients(&mut ctx));
+            hosts.insert(parts[0].to_owned(), ctx);
         }
-    }
-
-    #[inline]
-    fn local_addr(&mut self) -> io::Result<SocketAddr> {
-        match *self {
-            HttpListener::Http(ref mut tcp) => tcp.local_addr(),
-            HttpListener::Https(ref mut tcp, _) => tcp.local_addr(),
-        }
-    }
-}
-
-
-#[doc(hidden)]
-pub struct CloneTcpStream(TcpStream);
-
-impl Clone for CloneTcpStream{
-    #[inline]
-    fn clone(&self) -> CloneTcpStream {
-        CloneTcpStream(self.0.try_clone().unwrap())
-    }
-}
-
-impl Read for CloneTcpStream {
-    #[inline]
-    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        self.0.read(buf)
-    }
-}
 
-impl Write for CloneTcpStream {
-    #[inline]
-    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        self.0.write(buf)
-    }
-    #[inline]
-    fn flush(&mut self) -> io::Result<()> {
-        self.0.flush()
+        Ok(TlsAcceptor::with_sni(default, hosts))
     }
-}
 
-/// A wrapper around a TcpStream.
-#[derive(Clone)]
-pub enum HttpStream {
-    /// A stream over the HTTP protocol.
-    Http(CloneTcpStream),
-    /// A stream over the HTTP protocol, protected by SSL.
-    Https(SslStream<CloneTcpStream>),
-}
-
-impl fmt::Debug for HttpStream {
-  fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
-    match *self {
-      HttpStream::Http(_) => write!(fmt, "Http HttpStream"),
-      HttpStream::Https(_) => write!(fmt, "Https HttpStream"),
-    }
-  }
-}
-
-impl Read for HttpStream {
-    #[inline]
-    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        match *self {
-            HttpStream::Http(ref mut inner) => inner.read(buf),
-            HttpStream::Https(ref mut inner) => inner.read(buf)
+    fn verify_clients(&self, ctx: &mut SslContext) -> io::Result<()> {
+        match self.client_ca {
+            Some((ref ca_file, required)) => tls::verify_client_certs(ctx, Path::new(ca_file), required),
+            None => Ok(()),
         }
     }
 }
 
-impl Write for HttpStream {
-    #[inline]
-    fn write(&mut self, msg: &[u8]) -> io::Result<usize> {
-        match *self {
-            HttpStream::Http(ref mut inner) => inner.write(msg),
-            HttpStream::Https(ref mut inner) => inner.write(msg)
+/// Rebuilds the TLS acceptor from `config` on every SIGHUP
+///
+/// If the files cannot be loaded, the old certificates stay in use.
+fn reload_on_sighup(config: TlsConfig, tls: ReloadableAcceptor) {
+    let mut signals = match Signals::new(&[SIGHUP]) {
+        Ok(s) => s,
+        Err(err) => {
+            error!("Cannot catch SIGHUP, TLS reload disabled: {:?}", err);
+            return;
         }
-    }
-    #[inline]
-    fn flush(&mut self) -> io::Result<()> {
-        match *self {
-            HttpStream::Http(ref mut inner) => inner.flush(),
-            HttpStream::Https(ref mut inner) => inner.flush(),
+    };
+
+    while let Ok(..) = signals.recv() {
+        match config.acceptor() {
+            Ok(acceptor) => {
+                tls.reload(acceptor);
+                info!("Reloaded TLS certificates");
+            },
+            Err(err) => error!("Failed to reload TLS certificates, keeping the old ones: {:?}", err),
         }
     }
 }
 
-impl NetworkStream for HttpStream {
-    #[inline]
-    fn peer_addr(&mut self) -> io::Result<SocketAddr> {
-        match *self {
-            HttpStream::Http(ref mut inner) => inner.0.peer_addr(),
-            HttpStream::Https(ref mut inner) => inner.get_mut().0.peer_addr()
+/// Logs the number of connections closed by the limits on every SIGUSR1
+fn report_on_sigusr1(stats: Arc<ServerStats>) {
+    let mut signals = match Signals::new(&[SIGUSR1]) {
+        Ok(s) => s,
+        Err(err) => {
+            error!("Cannot catch SIGUSR1, stats report disabled: {:?}", err);
+            return;
         }
-    }
+    };
 
-    #[inline]
-    fn close(&mut self, how: Shutdown) -> io::Result<()> {
-        // #[inline]
-        // fn shutdown(tcp: &mut TcpStream, how: Shutdown) -> io::Result<()> {
-        //     match tcp.shutdown(how) {
-        //         Ok(_) => Ok(()),
-        //         // see https://github.com/hyperium/hyper/issues/508
-        //         Err(ref e) if e.kind() == io::ErrorKind::NotConnected => Ok(()),
-        //         err => err
-        //     }
-        // }
+    while let Ok(..) = signals.recv() {
+        info!("Closed connections: {} slow handshakes, {} idle, {} slow headers, {} slow bodies, {} too large",
+              stats.handshake_timeouts(), stats.idle_timeouts(), stats.header_timeouts(),
+              stats.slow_bodies(), stats.too_large());
+    }
+}
 
-        // match *self {
-        //     HttpStream::Http(ref mut inner) => shutdown(&mut inner.0, how),
-        //     HttpStream::Https(ref mut inner) => shutdown(&mut inner.get_mut().0, how)
-        // }
-        Ok(())
+/// Parses a timeout in milliseconds, where 0 disables it
+fn timeout_ms(value: Option<&str>, default: u64) -> Option<u64> {
+    match value.map(|v| v.parse().unwrap()).unwrap_or(default) {
+        0 => None,
+        ms => Some(ms),
     }
 }
 
@@ -339,41 +145,69 @@ fn main() {
                     .help("Listening on this address"))
             .arg(Arg::with_name("THREADS").short("t").long("threads").takes_value(true)
                     .help("Number of threads"))
+            .arg(Arg::with_name("SHARDED").short("s").long("sharded")
+                    .help("Accept on one SO_REUSEPORT listener per thread (Linux only)"))
+            .arg(Arg::with_name("CERT").long("cert").takes_value(true).requires("KEY")
+                    .help("Serve HTTPS with this PEM certificate"))
+            .arg(Arg::with_name("KEY").long("key").takes_value(true).requires("CERT")
+                    .help("PEM private key of the certificate"))
+            .arg(Arg::with_name("SNI").long("sni").takes_value(true).multiple(true).requires("CERT")
+                    .help("Serve HOST with its own certificate, given as HOST:CERT:KEY"))
+            .arg(Arg::with_name("CLIENT_CA").long("client-ca").takes_value(true).requires("CERT")
+                    .help("Require client certificates signed by a CA in this PEM bundle"))
+            .arg(Arg::with_name("CLIENT_CERT_OPTIONAL").long("client-cert-optional").requires("CLIENT_CA")
+                    .help("Also accept clients without a certificate"))
+            .arg(Arg::with_name("IDLE_TIMEOUT").long("idle-timeout").takes_value(true)
+                    .help("Close keep-alive connections idle for this many milliseconds, 0 to disable"))
+            .arg(Arg::with_name("HEADER_TIMEOUT").long("header-timeout").takes_value(true)
+                    .help("Answer 408 if the request headers take longer than this many milliseconds, 0 to disable"))
+            .arg(Arg::with_name("MIN_BODY_RATE").long("min-body-rate").takes_value(true)
+                    .help("Close connections sending the request body slower than this many bytes per second"))
+            .arg(Arg::with_name("MAX_BODY").long("max-body").takes_value(true)
+                    .help("Answer 413 to request bodies larger than this many bytes"))
+            .arg(Arg::with_name("MAX_HEADER_BYTES").long("max-header-bytes").takes_value(true)
+                    .help("Answer 431 to request headers larger than this many bytes"))
             .get_matches();
 
-    let bind_addr = matches.value_of("BIND").unwrap().to_owned();
-
-    Scheduler::run(move|| {
-        // let addr = bind_addr.parse().unwrap();
-        // let server = match &addr {
-        //     &SocketAddr::V4(..) => TcpSocket::v4(),
-        //     &SocketAddr::V6(..) => TcpSocket::v6(),
-        // }.unwrap();
-        // server.set_reuseaddr(true).unwrap();
-        // // server.set_reuseport(true).unwrap();
-
-        // server.bind(&addr).unwrap();
-        // let server = server.listen(2048).unwrap();
-
-        // info!("Listening on {:?}", server.local_addr().unwrap());
-
-        // loop {
-        //     let stream = server.accept().unwrap();
-        //     let addr = stream.peer_addr().unwrap();
-        //     info!("Accept connection: {:?}", addr);
-
-        //     Scheduler::spawn(move|| {
-        //         debug!("Begin handling {:?}", addr);
-        //         Worker(&echo).handle_connection(&mut stream);
-        //     });
-        // }
-
-        let mut listener = HttpListener::http(&bind_addr[..]).unwrap();
-
-        loop {
-            let mut stream = listener.accept().unwrap();
-
-            Scheduler::spawn(move|| Worker(&echo).handle_connection(&mut stream));
-        }
-    }, matches.value_of("THREADS").unwrap_or("1").parse().unwrap());
+    let bind_addr = matches.value_of("BIND").unwrap();
+    let threads = matches.value_of("THREADS").unwrap_or("1").parse().unwrap();
+    let tls_config = match (matches.value_of("CERT"), matches.value_of("KEY")) {
+        (Some(cert), Some(key)) => Some(TlsConfig {
+            cert: cert.to_owned(),
+            key: key.to_owned(),
+            sni: matches.values_of("SNI").into_iter().flat_map(|v| v).map(|s| s.to_owned()).collect(),
+            client_ca: matches.value_of("CLIENT_CA")
+                              .map(|ca| (ca.to_owned(), !matches.is_present("CLIENT_CERT_OPTIONAL"))),
+        }),
+        _ => None,
+    };
+    let tls = tls_config.as_ref().map(|config| ReloadableAcceptor::new(config.acceptor().unwrap()));
+
+    let mut server = Server::new(bind_addr, SchedulerConfig::new().threads(threads)).unwrap()
+                            .sharded(matches.is_present("SHARDED"))
+                            .idle_timeout_ms(timeout_ms(matches.value_of("IDLE_TIMEOUT"),
+                                                        DEFAULT_IDLE_TIMEOUT_MS))
+                            .header_timeout_ms(timeout_ms(matches.value_of("HEADER_TIMEOUT"),
+                                                          DEFAULT_HEADER_TIMEOUT_MS));
+    if let Some(rate) = matches.value_of("MIN_BODY_RATE") {
+        server = server.min_body_rate(rate.parse().unwrap(), MIN_BODY_RATE_GRACE_MS);
+    }
+    let mut limits = Limits::new().max_body_bytes(matches.value_of("MAX_BODY").map(|v| v.parse().unwrap()));
+    if let Some(bytes) = matches.value_of("MAX_HEADER_BYTES") {
+        limits = limits.max_header_bytes(bytes.parse().unwrap());
+    }
+    server = server.limits(limits);
+
+    let stats = server.stats();
+    server = server.task(move|| report_on_sigusr1(stats));
+    if let (Some(config), Some(tls)) = (tls_config, tls) {
+        server = server.tls(tls.clone()).task(move|| reload_on_sighup(config, tls));
+    }
+
+    let router = Router::new()
+            .get("/", usage)
+            .get("/echo", usage)
+            .post("/echo", echo)
+            .get("/echo/*text", echo_path);
+    server.run(router).unwrap();
 }
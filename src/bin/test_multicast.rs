@@ -0,0 +1,36 @@
+extern crate cosupport;
+
+use std::net::Ipv4Addr;
+
+use cosupport::scheduler::Scheduler;
+use cosupport::net::udp::UdpSocket;
+
+const MESSAGE: &'static [u8] = b"Hello multicast";
+
+fn main() {
+    Scheduler::run(|| {
+        let group = Ipv4Addr::new(239, 255, 42, 98);
+        let loopback = Ipv4Addr::new(127, 0, 0, 1);
+
+        let receiver = UdpSocket::bind("0.0.0.0:0").unwrap();
+        receiver.join_multicast_v4(&group, &loopback).unwrap();
+        let port = receiver.local_addr().unwrap().port();
+
+        Scheduler::spawn(move|| {
+            let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+            sender.set_multicast_if_v4(&loopback).unwrap();
+            sender.set_multicast_loop_v4(true).unwrap();
+            sender.set_multicast_ttl_v4(1).unwrap();
+
+            sender.send_to(MESSAGE, (group, port)).unwrap();
+            println!("Sent {} bytes to {}:{}", MESSAGE.len(), group, port);
+        });
+
+        let mut buf = [0; 64];
+        let (len, peer_addr) = receiver.recv_from(&mut buf).unwrap();
+        println!("Received {} bytes from {:?}", len, peer_addr);
+        assert_eq!(&buf[..len], MESSAGE);
+
+        receiver.leave_multicast_v4(&group, &loopback).unwrap();
+    }, 1);
+}
@@ -0,0 +1,76 @@
+extern crate cosupport;
+extern crate openssl;
+
+use std::env;
+use std::fs::File;
+use std::io::{Read, Write};
+
+use openssl::crypto::hash::Type::SHA256;
+use openssl::x509::X509Generator;
+
+use cosupport::scheduler::Scheduler;
+use cosupport::net::tcp::{TcpListener, TcpStream};
+use cosupport::net::tls::{TlsAcceptor, TlsConnector};
+
+const MESSAGE: &'static [u8] = b"Hello TLS";
+
+fn main() {
+    // Self-signed certificate for localhost, which the client trusts as its only CA
+    let (cert, pkey) = X509Generator::new()
+            .set_bitlength(2048)
+            .set_valid_period(1)
+            .set_CN("localhost")
+            .set_sign_hash(SHA256)
+            .generate()
+            .unwrap();
+
+    let dir = env::temp_dir();
+    let cert_path = dir.join("cosupport-test-tls.crt");
+    let key_path = dir.join("cosupport-test-tls.key");
+    cert.write_pem(&mut File::create(&cert_path).unwrap()).unwrap();
+    pkey.write_pem(&mut File::create(&key_path).unwrap()).unwrap();
+
+    let acceptor = TlsAcceptor::from_pem_files(&cert_path, &key_path).unwrap();
+    let connector = TlsConnector::verifying(&cert_path).unwrap();
+
+    Scheduler::run(move|| {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        Scheduler::spawn(move|| {
+            // One connection is echoed, the other one is dropped by the client after the handshake
+            for _ in 0..2 {
+                let stream = listener.accept().unwrap();
+                let mut stream = match acceptor.accept(stream) {
+                    Ok(s) => s,
+                    Err(err) => {
+                        println!("Server handshake failed: {:?}", err);
+                        continue;
+                    }
+                };
+
+                let mut buf = [0; 64];
+                match stream.read(&mut buf) {
+                    Ok(len) if len > 0 => stream.write_all(&buf[..len]).unwrap(),
+                    _ => (),
+                }
+            }
+        });
+
+        let stream = TcpStream::connect(addr).unwrap();
+        let mut stream = connector.connect("localhost", stream).unwrap();
+        stream.write_all(MESSAGE).unwrap();
+
+        let mut buf = [0; 64];
+        let len = stream.read(&mut buf).unwrap();
+        println!("Received {} bytes over TLS", len);
+        assert_eq!(&buf[..len], MESSAGE);
+
+        let stream = TcpStream::connect(addr).unwrap();
+        assert!(connector.connect("example.com", stream).is_err());
+        println!("Hostname mismatch rejected");
+    }, 1);
+
+    let _ = std::fs::remove_file(&cert_path);
+    let _ = std::fs::remove_file(&key_path);
+}
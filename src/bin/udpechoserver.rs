@@ -1,17 +1,19 @@
 extern crate clap;
 #[macro_use] extern crate log;
 extern crate env_logger;
-extern crate mio;
 
 extern crate cosupport;
 
-use clap::{Arg, App};
+use std::net::SocketAddr;
 
-use mio::buf::RingBuf;
+use clap::{Arg, App};
 
 use cosupport::scheduler::Scheduler;
 use cosupport::net::udp::UdpSocket;
 
+const BATCH_SIZE: usize = 32;
+const DATAGRAM_SIZE: usize = 1024;
+
 fn main() {
     env_logger::init().unwrap();
 
@@ -27,18 +29,40 @@ fn main() {
     let bind_addr = matches.value_of("BIND").unwrap().to_owned();
 
     Scheduler::run(move|| {
-        let addr = bind_addr.parse().unwrap();
-        let server = UdpSocket::bound(&addr).unwrap();
+        let server = UdpSocket::bind(&bind_addr[..]).unwrap();
 
         info!("Listening on {:?}", server.local_addr().unwrap());
 
-        let mut buf = RingBuf::new(1024);
+        let mut storage = vec![0; BATCH_SIZE * DATAGRAM_SIZE];
 
         loop {
-            let peer_addr = server.recv_from(&mut buf).unwrap().unwrap();
-            info!("Accept connection: {:?}", peer_addr);
+            let received = {
+                let mut bufs = storage.chunks_mut(DATAGRAM_SIZE).collect::<Vec<&mut [u8]>>();
+                match server.recv_batch(&mut bufs) {
+                    Ok(received) => received,
+                    Err(err) => {
+                        error!("Failed to receive datagrams: {:?}", err);
+                        continue;
+                    }
+                }
+            };
+            info!("Received {} datagrams", received.len());
+
+            let replies = storage.chunks(DATAGRAM_SIZE).zip(received.iter())
+                    .map(|(buf, &(len, peer_addr))| (&buf[..len], peer_addr))
+                    .collect::<Vec<(&[u8], SocketAddr)>>();
 
-            server.send_to(&mut buf, &peer_addr).unwrap();
+            let mut sent = 0;
+            while sent < replies.len() {
+                match server.send_batch(&replies[sent..]) {
+                    Ok(n) => sent += n,
+                    Err(err) => {
+                        // The error belongs to the first unsent datagram, drop it and go on
+                        error!("Failed to echo to {}: {:?}", replies[sent].1, err);
+                        sent += 1;
+                    }
+                }
+            }
         }
     }, matches.value_of("THREADS").unwrap_or("1").parse().unwrap());
 }
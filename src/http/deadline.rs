@@ -0,0 +1,245 @@
+use std::cmp;
+use std::io::{self, Read};
+use std::sync::{Arc, Mutex};
+
+use time::precise_time_ns;
+
+enum Limit {
+    Unlimited,
+    /// Reads fail once this point in time (`precise_time_ns`) has passed
+    Deadline(u64),
+    /// Reads fail once fewer than `bytes_per_sec` have arrived on average since `start`,
+    /// not counting the first `grace_ms`
+    MinRate {
+        start: u64,
+        bytes: u64,
+        bytes_per_sec: u64,
+        grace_ms: u64,
+    },
+}
+
+struct State {
+    limit: Limit,
+    violated: bool,
+    /// Bytes which may still be read, `None` if unlimited
+    budget: Option<u64>,
+    exhausted: bool,
+    /// Line breaks in a row at the end of the bytes read so far, `None` unless reads stop at the
+    /// end of the request headers
+    header_end: Option<u8>,
+    /// Bytes read past the end of the headers, returned by the next reads before the stream's
+    held_back: Vec<u8>,
+}
+
+/// Time and size limits on the reads of a connection, shared by all clones of its stream
+///
+/// The server switches them between the phases of a request, e.g. a deadline while the headers are
+/// read and a minimum transfer rate while the handler reads the body. Reads which would break the
+/// time limit fail with `TimedOut`, reads past the size limit fail with `Other`.
+///
+/// The waiting is bounded where the connection blocks, on the socket, with `remaining_ms` and
+/// `after_socket_read`. Bytes are counted on the plaintext side, after TLS, with `allowed_len` and
+/// `after_read`, so rates and sizes do not depend on the record overhead.
+///
+/// While the headers are read, reads stop at their end and hold the bytes after it back. The
+/// request parser then buffers none of the body, so all of it is counted by the body limits.
+#[derive(Clone)]
+pub struct ReadLimit(Arc<Mutex<State>>);
+
+impl ReadLimit {
+    pub fn new() -> ReadLimit {
+        ReadLimit(Arc::new(Mutex::new(State {
+            limit: Limit::Unlimited,
+            violated: false,
+            budget: None,
+            exhausted: false,
+            header_end: None,
+            held_back: Vec::new(),
+        })))
+    }
+
+    /// Removes both limits and the stop at the end of the headers, and forgets earlier violations
+    pub fn clear(&self) {
+        let mut state = self.0.lock().unwrap();
+        state.limit = Limit::Unlimited;
+        state.violated = false;
+        state.budget = None;
+        state.exhausted = false;
+        state.header_end = None;
+    }
+
+    /// Makes reads stop at the end of the request headers, `buffered` are the bytes of the request
+    /// which were already read
+    ///
+    /// Returns how many of `buffered` are past the end of the headers, they are not counted by
+    /// later limits.
+    pub fn stop_at_header_end(&self, buffered: &[u8]) -> u64 {
+        let mut state = self.0.lock().unwrap();
+        let mut newlines = 0;
+        match find_header_end(&mut newlines, buffered) {
+            Some(end) => {
+                state.header_end = None;
+                (buffered.len() - end) as u64
+            },
+            None => {
+                state.header_end = Some(newlines);
+                0
+            }
+        }
+    }
+
+    /// Moves held back bytes into `buf`, `None` if there are none
+    pub fn take_held_back(&self, buf: &mut [u8]) -> Option<usize> {
+        let mut state = self.0.lock().unwrap();
+        if state.held_back.is_empty() {
+            return None;
+        }
+        let len = (&state.held_back[..]).read(buf).unwrap();
+        state.held_back = state.held_back[len..].to_vec();
+        Some(len)
+    }
+
+    /// Reads must be done within `timeout_ms` from now. `None` removes the limit.
+    pub fn set_deadline(&self, timeout_ms: Option<u64>) {
+        let mut state = self.0.lock().unwrap();
+        state.violated = false;
+        state.limit = match timeout_ms {
+            Some(ms) => Limit::Deadline(precise_time_ns() + ms * 1_000_000),
+            None => Limit::Unlimited,
+        };
+    }
+
+    /// From now on, at least `bytes_per_sec` must arrive on average after the first `grace_ms`
+    pub fn set_min_rate(&self, bytes_per_sec: u64, grace_ms: u64) {
+        let mut state = self.0.lock().unwrap();
+        state.violated = false;
+        if bytes_per_sec == 0 {
+            state.limit = Limit::Unlimited;
+            return;
+        }
+        state.limit = Limit::MinRate {
+            start: precise_time_ns(),
+            bytes: 0,
+            bytes_per_sec: bytes_per_sec,
+            grace_ms: grace_ms,
+        };
+    }
+
+    /// From now on, at most `max_bytes` may be read. `None` removes the limit.
+    pub fn set_max_bytes(&self, max_bytes: Option<u64>) {
+        let mut state = self.0.lock().unwrap();
+        state.budget = max_bytes;
+        state.exhausted = false;
+    }
+
+    /// Whether a read failed because of the current time limit
+    pub fn violated(&self) -> bool {
+        self.0.lock().unwrap().violated
+    }
+
+    /// Whether a read failed because of the current size limit
+    pub fn exhausted(&self) -> bool {
+        self.0.lock().unwrap().exhausted
+    }
+
+    /// How many of `len` bytes the next read may return
+    ///
+    /// Fails and marks the size limit as exhausted if nothing may be read anymore.
+    pub fn allowed_len(&self, len: usize) -> io::Result<usize> {
+        let mut state = self.0.lock().unwrap();
+        match state.budget {
+            None => Ok(len),
+            Some(0) if len > 0 => {
+                state.exhausted = true;
+                Err(io::Error::new(io::ErrorKind::Other, "read size limit exceeded"))
+            },
+            Some(budget) => Ok(cmp::min(len as u64, budget) as usize),
+        }
+    }
+
+    /// Milliseconds the next read may wait, `None` if unlimited
+    ///
+    /// Fails with `TimedOut` and marks the limit as violated if no time is left.
+    pub fn remaining_ms(&self) -> io::Result<Option<u64>> {
+        let mut state = self.0.lock().unwrap();
+        let deadline = match state.limit {
+            Limit::Unlimited => return Ok(None),
+            Limit::Deadline(deadline) => deadline,
+            Limit::MinRate { start, bytes, bytes_per_sec, grace_ms } => {
+                // The next byte has to arrive before the average drops below the rate
+                let allowed_ms = (bytes + 1) * 1000 / bytes_per_sec;
+                start + cmp::max(allowed_ms, grace_ms) * 1_000_000
+            }
+        };
+
+        let now = precise_time_ns();
+        if now >= deadline {
+            state.violated = true;
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "read time limit exceeded"));
+        }
+
+        // Round up, a zero timeout would not wait at all
+        Ok(Some((deadline - now + 999_999) / 1_000_000))
+    }
+
+    /// Records the result of a socket read which waited at most `remaining_ms`
+    pub fn after_socket_read(&self, result: &io::Result<usize>) {
+        if let Err(ref err) = *result {
+            if err.kind() == io::ErrorKind::TimedOut {
+                let mut state = self.0.lock().unwrap();
+                state.violated = match state.limit {
+                    Limit::Unlimited => false,
+                    _ => true,
+                };
+            }
+        }
+    }
+
+    /// Counts the plaintext bytes `read` by a read which asked for at most `allowed_len`
+    ///
+    /// Returns how many of them the read may return, the rest is held back.
+    pub fn after_read(&self, read: &[u8]) -> usize {
+        let mut state = self.0.lock().unwrap();
+        let mut len = read.len();
+        if let Some(mut newlines) = state.header_end {
+            match find_header_end(&mut newlines, read) {
+                Some(end) => {
+                    let mut held_back = read[end..].to_vec();
+                    held_back.extend(state.held_back.iter().cloned());
+                    state.held_back = held_back;
+                    state.header_end = None;
+                    len = end;
+                },
+                None => state.header_end = Some(newlines),
+            }
+        }
+
+        if let Limit::MinRate { ref mut bytes, .. } = state.limit {
+            *bytes += len as u64;
+        }
+        if let Some(ref mut budget) = state.budget {
+            *budget -= len as u64;
+        }
+        len
+    }
+}
+
+/// Position right after the empty line which ends the headers of a message in `buf`
+///
+/// `newlines` are the line breaks in a row before `buf`, and are updated for the next call. Bare
+/// `\n` line breaks are accepted like the parser does.
+pub fn find_header_end(newlines: &mut u8, buf: &[u8]) -> Option<usize> {
+    for (i, &byte) in buf.iter().enumerate() {
+        match byte {
+            b'\n' => {
+                *newlines += 1;
+                if *newlines == 2 {
+                    return Some(i + 1);
+                }
+            },
+            b'\r' => (),
+            _ => *newlines = 0,
+        }
+    }
+    None
+}
@@ -0,0 +1,105 @@
+use hyper::server::Request;
+use hyper::header::ContentLength;
+use hyper::status::StatusCode;
+use hyper::uri::RequestUri;
+
+/// Default maximum number of request headers, also the most the request parser accepts
+pub const DEFAULT_MAX_HEADERS: usize = 100;
+/// Default maximum size of the request line and headers together
+pub const DEFAULT_MAX_HEADER_BYTES: u64 = 32 * 1024;
+/// Default maximum length of the request target
+pub const DEFAULT_MAX_URI_LEN: usize = 8 * 1024;
+
+/// Size limits on the requests of a `Server`
+///
+/// Requests over them are answered with 413, 414 or 431 and the connection is closed, without
+/// handling the body. The rest of the request is still read and discarded for a short time, so
+/// the client receives the response before the connection closes.
+#[derive(Clone, Debug)]
+pub struct Limits {
+    max_headers: usize,
+    max_header_bytes: u64,
+    max_uri_len: usize,
+    max_body_bytes: Option<u64>,
+}
+
+impl Limits {
+    pub fn new() -> Limits {
+        Limits {
+            max_headers: DEFAULT_MAX_HEADERS,
+            max_header_bytes: DEFAULT_MAX_HEADER_BYTES,
+            max_uri_len: DEFAULT_MAX_URI_LEN,
+            max_body_bytes: None,
+        }
+    }
+
+    /// Maximum number of request headers
+    ///
+    /// The parser never accepts more than `DEFAULT_MAX_HEADERS`, larger values are clamped to it.
+    /// Requests with more headers are answered with 431 either way.
+    pub fn max_headers(mut self, count: usize) -> Limits {
+        self.max_headers = ::std::cmp::min(count, DEFAULT_MAX_HEADERS);
+        self
+    }
+
+    /// Maximum size of the request line and headers together, as sent on the wire
+    pub fn max_header_bytes(mut self, bytes: u64) -> Limits {
+        self.max_header_bytes = bytes;
+        self
+    }
+
+    /// Maximum length of the request target
+    pub fn max_uri_len(mut self, len: usize) -> Limits {
+        self.max_uri_len = len;
+        self
+    }
+
+    /// Maximum size of the request body, `None` for unlimited
+    ///
+    /// A larger `Content-Length` is rejected before the handler runs. Chunked bodies are cut off
+    /// once this many bytes, chunk framing included, were read after the headers.
+    pub fn max_body_bytes(mut self, bytes: Option<u64>) -> Limits {
+        self.max_body_bytes = bytes;
+        self
+    }
+
+    pub fn get_max_header_bytes(&self) -> u64 {
+        self.max_header_bytes
+    }
+
+    pub fn get_max_body_bytes(&self) -> Option<u64> {
+        self.max_body_bytes
+    }
+
+    /// Checks a parsed request before its body is read, returning the status to reject it with
+    pub fn check(&self, req: &Request) -> Result<(), StatusCode> {
+        if uri_len(&req.uri) > self.max_uri_len {
+            return Err(StatusCode::UriTooLong);
+        }
+
+        if req.headers.len() > self.max_headers {
+            return Err(StatusCode::RequestHeaderFieldsTooLarge);
+        }
+
+        match (self.max_body_bytes, req.headers.get::<ContentLength>()) {
+            (Some(max), Some(&ContentLength(len))) if len > max => Err(StatusCode::PayloadTooLarge),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits::new()
+    }
+}
+
+/// Length of the request target as it was sent
+fn uri_len(uri: &RequestUri) -> usize {
+    match *uri {
+        RequestUri::AbsolutePath(ref path) => path.len(),
+        RequestUri::AbsoluteUri(ref url) => url.to_string().len(),
+        RequestUri::Authority(ref authority) => authority.len(),
+        RequestUri::Star => 1,
+    }
+}
@@ -0,0 +1,279 @@
+use std::collections::HashMap;
+use std::net::{ToSocketAddrs, SocketAddr, Shutdown};
+use std::path::Path;
+use std::io::{self, Read, Write};
+use std::fmt;
+
+use hyper;
+use hyper::net::{NetworkListener, NetworkStream};
+
+use openssl::ssl::SslContext;
+
+use net::tcp::{TcpStream, TcpListener};
+use net::tls::{self, TlsAcceptor, ReloadableAcceptor, TlsStream, PeerIdentity};
+
+use super::deadline::ReadLimit;
+
+/// Protocols offered with ALPN, in the order of preference
+///
+/// Only HTTP/1.1 is served, so `h2` is not offered. Clients preferring it fall back to HTTP/1.1.
+pub const ALPN_PROTOCOLS: &'static [&'static [u8]] = &[b"http/1.1"];
+
+/// A `NetworkListener` for `HttpStream`s.
+pub enum HttpListener {
+    /// Http variant.
+    Http(TcpListener),
+    /// Https variant. The TLS handshake is done by `HttpStream::establish`.
+    Https(TcpListener, ReloadableAcceptor)
+}
+
+impl Clone for HttpListener {
+    fn clone(&self) -> HttpListener {
+        match *self {
+            HttpListener::Http(ref tcp) => HttpListener::Http(tcp.try_clone().unwrap()),
+            HttpListener::Https(ref tcp, ref tls) => HttpListener::Https(tcp.try_clone().unwrap(), tls.clone()),
+        }
+    }
+}
+
+impl HttpListener {
+
+    /// Start listening to an address over HTTP.
+    pub fn http<To: ToSocketAddrs>(addr: To) -> hyper::Result<HttpListener> {
+        Ok(HttpListener::Http(try!(TcpListener::bind(addr))))
+    }
+
+    /// Start listening to an address over HTTPS.
+    ///
+    /// The two paths point to the certificate and key PEM files, in that order.
+    pub fn https<To: ToSocketAddrs>(addr: To, cert: &Path, key: &Path) -> hyper::Result<HttpListener> {
+        HttpListener::https_with_context(addr, try!(https_context(cert, key)))
+    }
+
+    /// Start listening to an address of HTTPS, choosing the SslContext by the SNI hostname
+    ///
+    /// Clients without SNI or with an unknown hostname get `default`.
+    pub fn https_sni<To: ToSocketAddrs>(addr: To, default: SslContext, hosts: HashMap<String, SslContext>)
+            -> hyper::Result<HttpListener> {
+        Ok(HttpListener::Https(try!(TcpListener::bind(addr)), ReloadableAcceptor::new(TlsAcceptor::with_sni(default, hosts))))
+    }
+
+    /// Start listening to an address of HTTPS using the given SslContext
+    pub fn https_with_context<To: ToSocketAddrs>(addr: To, ssl_context: SslContext) -> hyper::Result<HttpListener> {
+        Ok(HttpListener::Https(try!(TcpListener::bind(addr)), ReloadableAcceptor::new(TlsAcceptor::new(ssl_context))))
+    }
+
+    /// Accepts a TCP connection without doing the TLS handshake, see `HttpStream::establish`
+    pub fn accept_tcp(&self) -> io::Result<CloneTcpStream> {
+        match *self {
+            HttpListener::Http(ref tcp) => Ok(CloneTcpStream::new(try!(tcp.accept()))),
+            HttpListener::Https(ref tcp, _) => Ok(CloneTcpStream::new(try!(tcp.accept()))),
+        }
+    }
+
+    /// The acceptor which `HttpStream::establish` needs for connections of this listener
+    ///
+    /// This is a snapshot, it is not affected by later calls to `reload_tls`.
+    pub fn tls_acceptor(&self) -> Option<TlsAcceptor> {
+        match *self {
+            HttpListener::Http(..) => None,
+            HttpListener::Https(_, ref tls) => Some(tls.current()),
+        }
+    }
+
+    /// Uses `acceptor` for the connections accepted from now on. Does nothing on an HTTP listener.
+    pub fn reload_tls(&self, acceptor: TlsAcceptor) {
+        if let HttpListener::Https(_, ref tls) = *self {
+            tls.reload(acceptor);
+        }
+    }
+}
+
+impl NetworkListener for HttpListener {
+    type Stream = HttpStream;
+
+    #[inline]
+    fn accept(&mut self) -> hyper::Result<HttpStream> {
+        let stream = try!(self.accept_tcp());
+        Ok(try!(HttpStream::establish(stream, self.tls_acceptor().as_ref())))
+    }
+
+    #[inline]
+    fn local_addr(&mut self) -> io::Result<SocketAddr> {
+        match *self {
+            HttpListener::Http(ref mut tcp) => tcp.local_addr(),
+            HttpListener::Https(ref mut tcp, _) => tcp.local_addr(),
+        }
+    }
+}
+
+
+#[doc(hidden)]
+pub struct CloneTcpStream(TcpStream, ReadLimit);
+
+impl CloneTcpStream {
+    pub fn new(stream: TcpStream) -> CloneTcpStream {
+        CloneTcpStream(stream, ReadLimit::new())
+    }
+
+    /// Handle to the read limit shared by this stream and all its clones
+    pub fn read_limit(&self) -> ReadLimit {
+        self.1.clone()
+    }
+}
+
+impl Clone for CloneTcpStream{
+    #[inline]
+    fn clone(&self) -> CloneTcpStream {
+        CloneTcpStream(self.0.try_clone().unwrap(), self.1.clone())
+    }
+}
+
+impl Read for CloneTcpStream {
+    #[inline]
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let result = match try!(self.1.remaining_ms()) {
+            None => self.0.read(buf),
+            Some(timeout_ms) => self.0.read_timeout(buf, timeout_ms),
+        };
+        self.1.after_socket_read(&result);
+        result
+    }
+}
+
+impl Write for CloneTcpStream {
+    #[inline]
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+    #[inline]
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+/// A wrapper around a TcpStream.
+#[derive(Clone)]
+pub enum HttpStream {
+    /// A stream over the HTTP protocol.
+    Http(CloneTcpStream),
+    /// A stream over the HTTP protocol, protected by SSL.
+    Https(TlsStream<CloneTcpStream>),
+}
+
+impl HttpStream {
+    /// Wraps an accepted connection, doing the TLS handshake if `tls` is given
+    ///
+    /// The handshake parks the current coroutine, so call this in the per-connection coroutine
+    /// rather than in the accept loop.
+    pub fn establish(stream: CloneTcpStream, tls: Option<&TlsAcceptor>) -> io::Result<HttpStream> {
+        match tls {
+            None => Ok(HttpStream::Http(stream)),
+            Some(tls) => Ok(HttpStream::Https(try!(tls.accept(stream)))),
+        }
+    }
+
+    /// Handle to the read limit of the underlying connection
+    pub fn read_limit(&self) -> ReadLimit {
+        self.limit().clone()
+    }
+
+    fn limit(&self) -> &ReadLimit {
+        match *self {
+            HttpStream::Http(ref inner) => &inner.1,
+            HttpStream::Https(ref inner) => &inner.get_ref().1,
+        }
+    }
+
+    /// Protocol negotiated with ALPN, `None` for plain HTTP or if the client did not use ALPN
+    pub fn alpn_protocol(&self) -> Option<Vec<u8>> {
+        match *self {
+            HttpStream::Http(..) => None,
+            HttpStream::Https(ref inner) => inner.alpn_protocol(),
+        }
+    }
+
+    /// Identity in the client certificate, if the client sent one
+    pub fn peer_identity(&self) -> Option<PeerIdentity> {
+        match *self {
+            HttpStream::Http(..) => None,
+            HttpStream::Https(ref inner) => inner.peer_identity(),
+        }
+    }
+
+    /// Hostname requested with SNI
+    pub fn server_name(&self) -> Option<String> {
+        match *self {
+            HttpStream::Http(..) => None,
+            HttpStream::Https(ref inner) => inner.server_name(),
+        }
+    }
+}
+
+impl fmt::Debug for HttpStream {
+  fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      HttpStream::Http(_) => write!(fmt, "Http HttpStream"),
+      HttpStream::Https(_) => write!(fmt, "Https HttpStream"),
+    }
+  }
+}
+
+// The size limits apply to the plaintext, the time limits to the socket reads below it
+impl Read for HttpStream {
+    #[inline]
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = try!(self.limit().allowed_len(buf.len()));
+        let buf = &mut buf[..len];
+        let len = match self.limit().take_held_back(buf) {
+            Some(len) => len,
+            None => match *self {
+                HttpStream::Http(ref mut inner) => try!(inner.read(buf)),
+                HttpStream::Https(ref mut inner) => try!(inner.read(buf))
+            },
+        };
+        Ok(self.limit().after_read(&buf[..len]))
+    }
+}
+
+impl Write for HttpStream {
+    #[inline]
+    fn write(&mut self, msg: &[u8]) -> io::Result<usize> {
+        match *self {
+            HttpStream::Http(ref mut inner) => inner.write(msg),
+            HttpStream::Https(ref mut inner) => inner.write(msg)
+        }
+    }
+    #[inline]
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            HttpStream::Http(ref mut inner) => inner.flush(),
+            HttpStream::Https(ref mut inner) => inner.flush(),
+        }
+    }
+}
+
+impl NetworkStream for HttpStream {
+    #[inline]
+    fn peer_addr(&mut self) -> io::Result<SocketAddr> {
+        match *self {
+            HttpStream::Http(ref mut inner) => inner.0.peer_addr(),
+            HttpStream::Https(ref mut inner) => inner.get_mut().0.peer_addr()
+        }
+    }
+
+    #[inline]
+    fn close(&mut self, how: Shutdown) -> io::Result<()> {
+        match *self {
+            HttpStream::Http(ref mut inner) => inner.0.shutdown(how),
+            HttpStream::Https(ref mut inner) => inner.get_mut().0.shutdown(how)
+        }
+    }
+}
+
+/// Creates an SslContext with the certificate and key PEM files, offering `ALPN_PROTOCOLS`
+pub fn https_context(cert: &Path, key: &Path) -> io::Result<SslContext> {
+    let mut ctx = try!(tls::server_context(cert, key));
+    ctx.set_alpn_protocols(ALPN_PROTOCOLS);
+    Ok(ctx)
+}
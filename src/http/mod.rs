@@ -0,0 +1,14 @@
+//! Coroutine HTTP/1.1 server on top of hyper's request parser
+
+pub use self::listener::{HttpListener, HttpStream, CloneTcpStream, ALPN_PROTOCOLS, https_context};
+pub use self::deadline::ReadLimit;
+pub use self::limits::{Limits, DEFAULT_MAX_HEADERS, DEFAULT_MAX_HEADER_BYTES, DEFAULT_MAX_URI_LEN};
+pub use self::router::{Router, RouteHandler, Middleware, Params};
+pub use self::server::{Server, ServerStats, Handler, CLIENT_SUBJECT_HEADER, CLIENT_SAN_HEADER,
+                       DEFAULT_HEADER_TIMEOUT_MS, DEFAULT_IDLE_TIMEOUT_MS};
+
+mod deadline;
+mod limits;
+mod listener;
+mod router;
+mod server;
@@ -0,0 +1,398 @@
+use std::collections::HashMap;
+use std::io;
+
+use hyper::server::{Request, Response};
+use hyper::header::Allow;
+use hyper::method::Method;
+use hyper::status::StatusCode;
+use hyper::uri::RequestUri;
+
+use super::server::Handler;
+
+/// Handles the requests of one route of a `Router`
+pub trait RouteHandler: Send + Sync + 'static {
+    /// Writes the response to a request, given the parameters captured from its path
+    fn handle(&self, req: Request, res: Response, params: &Params);
+}
+
+impl<F> RouteHandler for F where F: Fn(Request, Response, &Params) + Send + Sync + 'static {
+    fn handle(&self, req: Request, res: Response, params: &Params) {
+        self(req, res, params)
+    }
+}
+
+/// Hooks which a `Router` runs around every request, including the ones without a route
+pub trait Middleware: Send + Sync + 'static {
+    /// Runs before routing, e.g. to check credentials or set common response headers
+    ///
+    /// Returning false answers the request with the status and headers set on `res`, without
+    /// routing it. The status is 403 unless the hook changed it.
+    fn before(&self, _req: &mut Request, _res: &mut Response) -> bool {
+        true
+    }
+
+    /// Runs after the response was written, with the status it was sent with
+    fn after(&self, _method: &Method, _path: &str, _status: StatusCode) {}
+}
+
+/// Parameters captured from the path of a request
+///
+/// Values are taken from the path as sent, without percent-decoding.
+#[derive(Clone, Debug)]
+pub struct Params {
+    values: HashMap<String, String>,
+}
+
+impl Params {
+    fn new() -> Params {
+        Params {
+            values: HashMap::new(),
+        }
+    }
+
+    /// Value of the `:name` segment, or of the `*name` wildcard
+    ///
+    /// An unnamed `*` wildcard is stored as `*`.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.values.get(name).map(|v| &v[..])
+    }
+}
+
+#[derive(Debug)]
+enum Segment {
+    /// Matches exactly this segment
+    Literal(String),
+    /// `:name`, matches any non-empty segment
+    Param(String),
+    /// `*name` or `*`, matches the rest of the path, which may be empty
+    Wildcard(String),
+}
+
+struct Route {
+    method: Method,
+    segments: Vec<Segment>,
+    handler: Box<RouteHandler>,
+}
+
+impl Route {
+    /// Matches `path` against the pattern of this route
+    fn matches(&self, path: &str) -> Option<Params> {
+        let mut params = Params::new();
+        let mut parts = path_segments(path);
+
+        for segment in self.segments.iter() {
+            match *segment {
+                Segment::Wildcard(ref name) => {
+                    let rest: Vec<&str> = parts.by_ref().collect();
+                    params.values.insert(name.clone(), rest.join("/"));
+                    return Some(params);
+                },
+                Segment::Literal(ref literal) => match parts.next() {
+                    Some(part) if part == &literal[..] => (),
+                    _ => return None,
+                },
+                Segment::Param(ref name) => match parts.next() {
+                    Some(part) if !part.is_empty() => {
+                        params.values.insert(name.clone(), part.to_owned());
+                    },
+                    _ => return None,
+                },
+            }
+        }
+
+        match parts.next() {
+            Some(..) => None,
+            None => Some(params),
+        }
+    }
+}
+
+/// Splits a path into its segments, `/` has one empty segment
+fn path_segments<'a>(path: &'a str) -> ::std::str::Split<'a, char> {
+    let path = if path.starts_with('/') { &path[1..] } else { path };
+    path.split('/')
+}
+
+/// Parses a route pattern like `/users/:id/files/*path`
+///
+/// Panics if a wildcard is not the last segment.
+fn parse_pattern(pattern: &str) -> Vec<Segment> {
+    let mut segments = Vec::new();
+    for part in path_segments(pattern) {
+        if let Some(&Segment::Wildcard(..)) = segments.last() {
+            panic!("wildcard must be the last segment of route {:?}", pattern);
+        }
+
+        let segment = if part.starts_with(':') && part.len() > 1 {
+            Segment::Param(part[1..].to_owned())
+        } else if part.starts_with('*') {
+            Segment::Wildcard(if part.len() > 1 { part[1..].to_owned() } else { "*".to_owned() })
+        } else {
+            Segment::Literal(part.to_owned())
+        };
+        segments.push(segment);
+    }
+    segments
+}
+
+/// Path of the request target without the query, `None` if it has no path
+fn request_path(uri: &RequestUri) -> Option<String> {
+    match *uri {
+        RequestUri::AbsolutePath(ref path) => Some(match path.find('?') {
+            Some(pos) => path[..pos].to_owned(),
+            None => path.clone(),
+        }),
+        RequestUri::AbsoluteUri(ref url) => url.serialize_path(),
+        RequestUri::Authority(..) | RequestUri::Star => None,
+    }
+}
+
+/// Dispatches requests to handlers by method and path pattern
+///
+/// Patterns are matched in the order they were added. A segment `:name` matches any non-empty
+/// segment, a last segment `*name` matches the rest of the path. Requests without a matching
+/// pattern are answered with 404, requests with a matching pattern but not method with 405 and
+/// the allowed methods.
+///
+/// HEAD requests without their own route are handled by the GET route, the server discards the
+/// body. OPTIONS requests without their own route are answered with the allowed methods.
+pub struct Router {
+    routes: Vec<Route>,
+    middleware: Vec<Box<Middleware>>,
+}
+
+impl Router {
+    pub fn new() -> Router {
+        Router {
+            routes: Vec::new(),
+            middleware: Vec::new(),
+        }
+    }
+
+    /// Handles requests with `method` whose path matches `pattern`
+    pub fn route<H: RouteHandler>(mut self, method: Method, pattern: &str, handler: H) -> Router {
+        self.routes.push(Route {
+            method: method,
+            segments: parse_pattern(pattern),
+            handler: Box::new(handler),
+        });
+        self
+    }
+
+    pub fn get<H: RouteHandler>(self, pattern: &str, handler: H) -> Router {
+        self.route(Method::Get, pattern, handler)
+    }
+
+    pub fn post<H: RouteHandler>(self, pattern: &str, handler: H) -> Router {
+        self.route(Method::Post, pattern, handler)
+    }
+
+    pub fn put<H: RouteHandler>(self, pattern: &str, handler: H) -> Router {
+        self.route(Method::Put, pattern, handler)
+    }
+
+    pub fn delete<H: RouteHandler>(self, pattern: &str, handler: H) -> Router {
+        self.route(Method::Delete, pattern, handler)
+    }
+
+    /// Runs `middleware` around every request, `before` hooks in the order they were added and
+    /// `after` hooks in reverse
+    pub fn middleware<M: Middleware>(mut self, middleware: M) -> Router {
+        self.middleware.push(Box::new(middleware));
+        self
+    }
+
+    /// Finds the route for a request, or the status and allowed methods to answer it with
+    fn find(&self, method: &Method, path: Option<&str>)
+            -> Result<(&Route, Params), (StatusCode, Vec<Method>)> {
+        let path = match path {
+            Some(path) => path,
+            None => return Err((StatusCode::NotFound, Vec::new())),
+        };
+
+        let mut allowed = Vec::new();
+        let mut get_route = None;
+        for route in self.routes.iter() {
+            if let Some(params) = route.matches(path) {
+                if route.method == *method {
+                    return Ok((route, params));
+                }
+                if route.method == Method::Get && get_route.is_none() {
+                    get_route = Some((route, params));
+                }
+                if !allowed.contains(&route.method) {
+                    allowed.push(route.method.clone());
+                }
+            }
+        }
+
+        if *method == Method::Head {
+            if let Some(found) = get_route {
+                return Ok(found);
+            }
+        }
+        if allowed.is_empty() {
+            return Err((StatusCode::NotFound, allowed));
+        }
+
+        if allowed.contains(&Method::Get) && !allowed.contains(&Method::Head) {
+            allowed.push(Method::Head);
+        }
+        if !allowed.contains(&Method::Options) {
+            allowed.push(Method::Options);
+        }
+        if *method == Method::Options {
+            Err((StatusCode::Ok, allowed))
+        } else {
+            Err((StatusCode::MethodNotAllowed, allowed))
+        }
+    }
+
+    /// Runs the `before` hooks and the route of a request, the response is sent when `res` is dropped
+    ///
+    /// The body of requests which are not routed is discarded, so the connection can be kept alive.
+    fn dispatch(&self, mut req: Request, mut res: Response, method: &Method, path: Option<&str>) {
+        for middleware in self.middleware.iter() {
+            let status = res.status();
+            if !middleware.before(&mut req, &mut res) {
+                if res.status() == status {
+                    *res.status_mut() = StatusCode::Forbidden;
+                }
+                let _ = io::copy(&mut req, &mut io::sink());
+                return;
+            }
+        }
+
+        match self.find(method, path) {
+            Ok((route, params)) => route.handler.handle(req, res, &params),
+            Err((status, allowed)) => {
+                debug!("no route for {} {:?}, answering {}", method, path, status);
+                let _ = io::copy(&mut req, &mut io::sink());
+                *res.status_mut() = status;
+                if !allowed.is_empty() {
+                    res.headers_mut().set(Allow(allowed));
+                }
+            }
+        }
+    }
+}
+
+impl Handler for Router {
+    fn handle(&self, req: Request, res: Response) {
+        let method = req.method.clone();
+        let path = request_path(&req.uri);
+        self.dispatch(req, res, &method, path.as_ref().map(|p| &p[..]));
+    }
+
+    fn finished(&self, method: &Method, uri: &RequestUri, status: StatusCode) {
+        let path = request_path(uri).unwrap_or(String::new());
+        for middleware in self.middleware.iter().rev() {
+            middleware.after(method, &path, status);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::Url;
+    use hyper::method::Method;
+    use hyper::server::{Request, Response};
+    use hyper::status::StatusCode;
+    use hyper::uri::RequestUri;
+
+    use super::{Router, Params, request_path};
+
+    fn noop(_: Request, _: Response, _: &Params) {}
+
+    fn router() -> Router {
+        Router::new()
+            .get("/", noop)
+            .get("/users/:id", noop)
+            .put("/users/:id", noop)
+            .get("/files/*path", noop)
+            .post("/upload/", noop)
+    }
+
+    fn param(router: &Router, method: Method, path: &str, name: &str) -> Option<String> {
+        match router.find(&method, Some(path)) {
+            Ok((_, params)) => params.get(name).map(|v| v.to_owned()),
+            Err((status, _)) => panic!("{} {} was answered with {}", method, path, status),
+        }
+    }
+
+    fn status(router: &Router, method: Method, path: &str) -> (StatusCode, Vec<Method>) {
+        match router.find(&method, Some(path)) {
+            Ok(..) => panic!("{} {} was routed", method, path),
+            Err(answer) => answer,
+        }
+    }
+
+    #[test]
+    fn captures_params() {
+        let router = router();
+        assert_eq!(param(&router, Method::Get, "/users/42", "id"), Some("42".to_owned()));
+        assert_eq!(param(&router, Method::Put, "/users/42", "id"), Some("42".to_owned()));
+        assert_eq!(param(&router, Method::Get, "/", "id"), None);
+        assert_eq!(status(&router, Method::Get, "/users/").0, StatusCode::NotFound);
+        assert_eq!(status(&router, Method::Get, "/users/42/posts").0, StatusCode::NotFound);
+    }
+
+    #[test]
+    fn wildcard_matches_rest() {
+        let router = router();
+        assert_eq!(param(&router, Method::Get, "/files/a/b/c", "path"), Some("a/b/c".to_owned()));
+        assert_eq!(param(&router, Method::Get, "/files/", "path"), Some("".to_owned()));
+        assert_eq!(param(&router, Method::Get, "/files", "path"), Some("".to_owned()));
+
+        let router = Router::new().get("/static/*", noop);
+        assert_eq!(param(&router, Method::Get, "/static/x/y", "*"), Some("x/y".to_owned()));
+    }
+
+    #[test]
+    fn trailing_slash_is_a_segment() {
+        let router = router();
+        assert!(router.find(&Method::Post, Some("/upload/")).is_ok());
+        assert_eq!(status(&router, Method::Post, "/upload").0, StatusCode::NotFound);
+        assert_eq!(status(&router, Method::Get, "/users/42/").0, StatusCode::NotFound);
+    }
+
+    #[test]
+    fn strips_query() {
+        let path = RequestUri::AbsolutePath("/users/42?x=1&y=/2".to_owned());
+        assert_eq!(request_path(&path), Some("/users/42".to_owned()));
+
+        let url = RequestUri::AbsoluteUri(Url::parse("http://example.com/users/42?x=1").unwrap());
+        assert_eq!(request_path(&url), Some("/users/42".to_owned()));
+
+        assert_eq!(request_path(&RequestUri::Star), None);
+    }
+
+    #[test]
+    fn not_found_and_method_not_allowed() {
+        let router = router();
+        assert_eq!(status(&router, Method::Get, "/missing"), (StatusCode::NotFound, vec![]));
+        assert_eq!(router.find(&Method::Get, None).err(), Some((StatusCode::NotFound, vec![])));
+
+        assert_eq!(status(&router, Method::Delete, "/users/42"),
+                   (StatusCode::MethodNotAllowed,
+                    vec![Method::Get, Method::Put, Method::Head, Method::Options]));
+        assert_eq!(status(&router, Method::Get, "/upload/"),
+                   (StatusCode::MethodNotAllowed, vec![Method::Post, Method::Options]));
+    }
+
+    #[test]
+    fn head_and_options() {
+        let router = router();
+        assert_eq!(param(&router, Method::Head, "/users/42", "id"), Some("42".to_owned()));
+        assert_eq!(status(&router, Method::Head, "/upload/").0, StatusCode::MethodNotAllowed);
+        assert_eq!(status(&router, Method::Options, "/users/42"),
+                   (StatusCode::Ok, vec![Method::Get, Method::Put, Method::Head, Method::Options]));
+        assert_eq!(status(&router, Method::Options, "/missing").0, StatusCode::NotFound);
+    }
+
+    #[test]
+    #[should_panic(expected = "wildcard must be the last segment")]
+    fn wildcard_in_the_middle_panics() {
+        Router::new().get("/files/*path/edit", noop);
+    }
+}
@@ -0,0 +1,625 @@
+use std::io::{self, BufRead, Read, Write, BufWriter};
+use std::cmp;
+use std::str;
+use std::net::{SocketAddr, ToSocketAddrs, Shutdown};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::mem;
+
+use hyper::http::should_keep_alive;
+use hyper::buffer::BufReader;
+use hyper::server::{Request, Response};
+use hyper::header::{Connection, Headers, Expect};
+use hyper::version::HttpVersion;
+use hyper::net::NetworkStream;
+use hyper::status::StatusCode;
+use hyper::uri::RequestUri;
+use hyper::error::Error;
+use hyper::method::Method;
+
+use scheduler::{Scheduler, SchedulerConfig};
+use net::tcp::TcpListener;
+use net::tls::{TlsAcceptor, ReloadableAcceptor, PeerIdentity};
+
+use super::listener::{HttpListener, HttpStream, CloneTcpStream};
+use super::deadline::{ReadLimit, find_header_end};
+use super::limits::Limits;
+
+/// Default time a client has to send the complete request headers
+pub const DEFAULT_HEADER_TIMEOUT_MS: u64 = 30_000;
+
+/// Default time a keep-alive connection may wait for its next request
+pub const DEFAULT_IDLE_TIMEOUT_MS: u64 = 60_000;
+
+/// How long the rest of a rejected request is read and discarded before the connection is closed
+const LINGER_TIMEOUT_MS: u64 = 2_000;
+/// How much of a rejected request is read and discarded at most
+const LINGER_MAX_BYTES: u64 = 64 * 1024;
+
+/// Carries the subject distinguished name of the verified client certificate to the handler, e.g.
+/// `CN=client,O=Example`
+pub const CLIENT_SUBJECT_HEADER: &'static str = "X-Client-Cert-Subject";
+/// Carries the comma separated subject alternative names of the verified client certificate
+pub const CLIENT_SAN_HEADER: &'static str = "X-Client-Cert-San";
+
+/// Handles the requests of a `Server`
+///
+/// It is shared by all connection coroutines, which may run on any scheduler thread.
+pub trait Handler: Send + Sync + 'static {
+    /// Writes the response to a request
+    fn handle(&self, req: Request, res: Response);
+
+    /// Decides whether a request with `Expect: 100-continue` may send its body
+    fn check_continue(&self, _: (&Method, &RequestUri, &Headers)) -> StatusCode {
+        StatusCode::Continue
+    }
+
+    /// Runs after the response to a request was written, with the status it was sent with
+    ///
+    /// Not called if no response could be written.
+    fn finished(&self, _method: &Method, _uri: &RequestUri, _status: StatusCode) {}
+}
+
+impl<F> Handler for F where F: Fn(Request, Response) + Send + Sync + 'static {
+    fn handle(&self, req: Request, res: Response) {
+        self(req, res)
+    }
+}
+
+/// Counters of the connections a `Server` closed for being too slow or too large
+pub struct ServerStats {
+    handshake_timeouts: AtomicUsize,
+    idle_timeouts: AtomicUsize,
+    header_timeouts: AtomicUsize,
+    slow_bodies: AtomicUsize,
+    too_large: AtomicUsize,
+}
+
+impl ServerStats {
+    fn new() -> ServerStats {
+        ServerStats {
+            handshake_timeouts: AtomicUsize::new(0),
+            idle_timeouts: AtomicUsize::new(0),
+            header_timeouts: AtomicUsize::new(0),
+            slow_bodies: AtomicUsize::new(0),
+            too_large: AtomicUsize::new(0),
+        }
+    }
+
+    /// TLS connections which did not complete the handshake within the header timeout
+    pub fn handshake_timeouts(&self) -> usize {
+        self.handshake_timeouts.load(Ordering::Relaxed)
+    }
+
+    /// Keep-alive connections which sent no new request within the idle timeout
+    pub fn idle_timeouts(&self) -> usize {
+        self.idle_timeouts.load(Ordering::Relaxed)
+    }
+
+    /// Connections which did not complete the request headers before the header deadline
+    pub fn header_timeouts(&self) -> usize {
+        self.header_timeouts.load(Ordering::Relaxed)
+    }
+
+    /// Connections which sent a request body slower than the minimum rate
+    pub fn slow_bodies(&self) -> usize {
+        self.slow_bodies.load(Ordering::Relaxed)
+    }
+
+    /// Connections which sent a request over the size limits
+    pub fn too_large(&self) -> usize {
+        self.too_large.load(Ordering::Relaxed)
+    }
+}
+
+/// A coroutine HTTP/1.1 server, running one coroutine per connection
+pub struct Server {
+    addr: SocketAddr,
+    config: SchedulerConfig,
+    tls: Option<ReloadableAcceptor>,
+    sharded: bool,
+    header_timeout_ms: Option<u64>,
+    idle_timeout_ms: Option<u64>,
+    min_body_rate: Option<(u64, u64)>,
+    limits: Limits,
+    stats: Arc<ServerStats>,
+    tasks: Vec<Box<FnMut() + Send + 'static>>,
+}
+
+impl Server {
+    /// Creates a plain HTTP server for the first address `addr` resolves to
+    pub fn new<A: ToSocketAddrs>(addr: A, config: SchedulerConfig) -> io::Result<Server> {
+        let addr = match try!(addr.to_socket_addrs()).next() {
+            Some(addr) => addr,
+            None => return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                              "could not resolve to any addresses")),
+        };
+
+        Ok(Server {
+            addr: addr,
+            config: config,
+            tls: None,
+            sharded: false,
+            header_timeout_ms: Some(DEFAULT_HEADER_TIMEOUT_MS),
+            idle_timeout_ms: Some(DEFAULT_IDLE_TIMEOUT_MS),
+            min_body_rate: None,
+            limits: Limits::new(),
+            stats: Arc::new(ServerStats::new()),
+            tasks: Vec::new(),
+        })
+    }
+
+    /// Serves HTTPS, doing the handshake in the connection coroutines
+    ///
+    /// Keep a clone of `acceptor` to swap the certificates while the server is running.
+    pub fn tls(mut self, acceptor: ReloadableAcceptor) -> Server {
+        self.tls = Some(acceptor);
+        self
+    }
+
+    /// Accepts on one `SO_REUSEPORT` listener per scheduler thread, see `TcpListener::bind_sharded`
+    ///
+    /// Only supported on Linux, elsewhere `run` fails.
+    pub fn sharded(mut self, sharded: bool) -> Server {
+        self.sharded = sharded;
+        self
+    }
+
+    /// Answers 408 and closes the connection if the request headers are not complete within
+    /// `timeout_ms` after they started. `None` waits forever.
+    ///
+    /// The TLS handshake of a new connection has the same deadline, and the connection is closed
+    /// if it takes longer.
+    pub fn header_timeout_ms(mut self, timeout_ms: Option<u64>) -> Server {
+        self.header_timeout_ms = timeout_ms;
+        self
+    }
+
+    /// Closes keep-alive connections which send no new request within `timeout_ms`. `None` waits forever.
+    pub fn idle_timeout_ms(mut self, timeout_ms: Option<u64>) -> Server {
+        self.idle_timeout_ms = timeout_ms;
+        self
+    }
+
+    /// Closes connections whose request body arrives slower than `bytes_per_sec` on average,
+    /// after the first `grace_ms`
+    pub fn min_body_rate(mut self, bytes_per_sec: u64, grace_ms: u64) -> Server {
+        self.min_body_rate = Some((bytes_per_sec, grace_ms));
+        self
+    }
+
+    /// Rejects requests over these size limits
+    pub fn limits(mut self, limits: Limits) -> Server {
+        self.limits = limits;
+        self
+    }
+
+    /// Counters of the connections closed by the limits, which stay valid while the server runs
+    pub fn stats(&self) -> Arc<ServerStats> {
+        self.stats.clone()
+    }
+
+    /// Runs `task` in its own coroutine next to the server, e.g. to wait for signals
+    pub fn task<F>(mut self, task: F) -> Server
+            where F: FnOnce() + Send + 'static {
+        let mut task = Some(task);
+        self.tasks.push(Box::new(move|| {
+            if let Some(task) = task.take() {
+                task();
+            }
+        }));
+        self
+    }
+
+    /// Starts the schedulers and serves `handler` until accepting fails
+    pub fn run<H: Handler>(self, handler: H) -> io::Result<()> {
+        let Server { addr, config, tls, sharded, header_timeout_ms, idle_timeout_ms, min_body_rate,
+                     limits, stats, tasks } = self;
+        let worker = Arc::new(Worker {
+            handler: handler,
+            header_timeout_ms: header_timeout_ms,
+            idle_timeout_ms: idle_timeout_ms,
+            min_body_rate: min_body_rate,
+            limits: limits,
+            stats: stats,
+        });
+        let result = Arc::new(Mutex::new(Ok(())));
+
+        {
+            let result = result.clone();
+            Scheduler::run_with_config(move|| {
+                for task in tasks.into_iter() {
+                    let mut task = task;
+                    Scheduler::spawn(move|| task());
+                }
+
+                let ret = if sharded {
+                    serve_sharded(addr, tls, worker)
+                } else {
+                    serve(addr, tls, worker)
+                };
+                *result.lock().unwrap() = ret;
+            }, &config);
+        }
+
+        let mut result = result.lock().unwrap();
+        mem::replace(&mut *result, Ok(()))
+    }
+}
+
+fn serve<H: Handler>(addr: SocketAddr, tls: Option<ReloadableAcceptor>, worker: Arc<Worker<H>>)
+        -> io::Result<()> {
+    let tcp = try!(TcpListener::bind(addr));
+    info!("Listening on {:?}", try!(tcp.local_addr()));
+
+    let listener = match tls {
+        Some(tls) => HttpListener::Https(tcp, tls),
+        None => HttpListener::Http(tcp),
+    };
+
+    loop {
+        let stream = try!(listener.accept_tcp());
+        let tls = listener.tls_acceptor();
+        let worker = worker.clone();
+
+        Scheduler::spawn(move|| worker.handle_connection(stream, tls));
+    }
+}
+
+fn serve_sharded<H: Handler>(addr: SocketAddr, tls: Option<ReloadableAcceptor>, worker: Arc<Worker<H>>)
+        -> io::Result<()> {
+    info!("Listening on {} with {} shards", addr, Scheduler::threads());
+
+    TcpListener::bind_sharded(addr, move|stream| {
+        let tls = tls.as_ref().map(|tls| tls.current());
+        worker.handle_connection(CloneTcpStream::new(stream), tls);
+    })
+}
+
+/// Serves the requests of the connections, shared by all connection coroutines
+struct Worker<H: Handler> {
+    handler: H,
+    header_timeout_ms: Option<u64>,
+    idle_timeout_ms: Option<u64>,
+    min_body_rate: Option<(u64, u64)>,
+    limits: Limits,
+    stats: Arc<ServerStats>,
+}
+
+impl<H: Handler> Worker<H> {
+
+    fn handle_connection(&self, stream: CloneTcpStream, tls: Option<TlsAcceptor>) {
+        // The handshake has the same deadline as the request headers, which follow it
+        let limit = stream.read_limit();
+        limit.set_deadline(self.header_timeout_ms);
+        let established = HttpStream::establish(stream, tls.as_ref());
+        let timed_out = limit.violated();
+        limit.clear();
+
+        let mut stream = match established {
+            Ok(s) => s,
+            Err(err) => {
+                if timed_out {
+                    debug!("TLS handshake timed out");
+                    self.stats.handshake_timeouts.fetch_add(1, Ordering::Relaxed);
+                } else {
+                    debug!("TLS handshake failed: {:?}", err);
+                }
+                return;
+            }
+        };
+        debug!("Negotiated protocol {:?}", stream.alpn_protocol());
+
+        let identity = stream.peer_identity();
+        self.handle_stream(&mut stream, identity);
+    }
+
+    fn handle_stream(&self, mut stream: &mut HttpStream, identity: Option<PeerIdentity>) {
+        debug!("Incoming stream");
+        let addr = match stream.peer_addr() {
+            Ok(addr) => addr,
+            Err(e) => {
+                error!("Peer Name error: {:?}", e);
+                return;
+            }
+        };
+
+        let limit = stream.read_limit();
+        // FIXME: Use Type ascription
+        let stream_clone: &mut NetworkStream = &mut stream.clone();
+        let rdr = BufReader::new(stream_clone);
+        let wrt = BufWriter::new(stream);
+
+        self.keep_alive_loop(rdr, wrt, addr, identity, limit);
+        debug!("keep_alive loop ending for {}", addr);
+    }
+
+    fn keep_alive_loop<W: Write>(&self, mut rdr: BufReader<&mut NetworkStream>, mut wrt: W, addr: SocketAddr,
+                                 identity: Option<PeerIdentity>, limit: ReadLimit) {
+        let mut keep_alive = true;
+        let mut first = true;
+        let mut linger = false;
+        while keep_alive {
+            if !first && !self.wait_next_request(&mut rdr, addr, &limit) {
+                break;
+            }
+            first = false;
+
+            // Bytes buffered while waiting for the request already count against the header size,
+            // unless they are past the end of the headers. Those only happen with pipelining and
+            // count against the body size instead.
+            let past_headers = limit.stop_at_header_end(rdr.get_buf());
+            let buffered = rdr.get_buf().len() as u64 - past_headers;
+            let max_header_bytes = self.limits.get_max_header_bytes();
+            limit.set_max_bytes(Some(max_header_bytes.saturating_sub(buffered)));
+            limit.set_deadline(self.header_timeout_ms);
+            let req = Request::new(&mut rdr, addr);
+            let headers_too_large = limit.exhausted();
+            limit.clear();
+
+            let mut req = match req {
+                Ok(req) => req,
+                Err(Error::Io(ref e)) if e.kind() == io::ErrorKind::ConnectionAborted => {
+                    trace!("tcp closed, cancelling keep-alive loop");
+                    break;
+                }
+                Err(Error::Io(..)) if headers_too_large => {
+                    debug!("request headers from {} are too large", addr);
+                    self.stats.too_large.fetch_add(1, Ordering::Relaxed);
+                    send_error(&mut wrt, StatusCode::RequestHeaderFieldsTooLarge);
+                    linger = true;
+                    break;
+                }
+                Err(Error::Io(ref e)) if e.kind() == io::ErrorKind::TimedOut => {
+                    debug!("timed out reading request headers from {}", addr);
+                    self.stats.header_timeouts.fetch_add(1, Ordering::Relaxed);
+                    send_error(&mut wrt, StatusCode::RequestTimeout);
+                    break;
+                }
+                Err(Error::Io(e)) => {
+                    debug!("ioerror in keepalive loop = {:?}", e);
+                    break;
+                }
+                Err(e) => {
+                    debug!("request error from {} = {:?}", addr, e);
+                    send_error(&mut wrt, error_status(&e));
+                    linger = true;
+                    break;
+                }
+            };
+
+            if let Err(status) = self.limits.check(&req) {
+                debug!("request from {} is over the size limits, answering {}", addr, status);
+                self.stats.too_large.fetch_add(1, Ordering::Relaxed);
+                send_error(&mut wrt, status);
+                linger = true;
+                break;
+            }
+
+            set_identity_headers(&mut req.headers, identity.as_ref());
+
+            if !self.handle_expect(&req, &mut wrt) {
+                break;
+            }
+
+            keep_alive = should_keep_alive(req.version, &req.headers);
+            let version = req.version;
+            let mut res_headers = Headers::new();
+            if !keep_alive {
+                res_headers.set(Connection::close());
+            }
+            if let Some((bytes_per_sec, grace_ms)) = self.min_body_rate {
+                limit.set_min_rate(bytes_per_sec, grace_ms);
+            }
+            let max_body_bytes = self.limits.get_max_body_bytes();
+            limit.set_max_bytes(max_body_bytes.map(|max| max.saturating_sub(past_headers)));
+            let method = req.method.clone();
+            let uri = req.uri.clone();
+            let status = {
+                let mut out = ResponseWriter::new(&mut wrt, method == Method::Head);
+                {
+                    let mut res = Response::new(&mut out, &mut res_headers);
+                    res.version = version;
+                    self.handler.handle(req, res);
+                }
+                out.status()
+            };
+            if let Some(status) = status {
+                self.handler.finished(&method, &uri, status);
+            }
+
+            let too_slow = limit.violated();
+            let too_large = limit.exhausted();
+            limit.clear();
+            if too_slow {
+                debug!("request body from {} is too slow, closing", addr);
+                self.stats.slow_bodies.fetch_add(1, Ordering::Relaxed);
+                break;
+            }
+            if too_large {
+                debug!("request body from {} is too large, closing", addr);
+                self.stats.too_large.fetch_add(1, Ordering::Relaxed);
+                linger = true;
+                break;
+            }
+
+            // if the request was keep-alive, we need to check that the server agrees
+            // if it wasn't, then the server cannot force it to be true anyways
+            if keep_alive {
+                keep_alive = should_keep_alive(version, &res_headers);
+            }
+
+            debug!("keep_alive = {:?} for {}", keep_alive, addr);
+        }
+
+        if linger {
+            close_lingering(&mut rdr, &mut wrt, &limit);
+        }
+    }
+
+    /// Waits until the next request on a keep-alive connection starts to arrive
+    ///
+    /// Returns false if the connection should be closed, because the client closed it or stayed
+    /// idle for too long.
+    fn wait_next_request(&self, rdr: &mut BufReader<&mut NetworkStream>, addr: SocketAddr, limit: &ReadLimit)
+            -> bool {
+        limit.set_deadline(self.idle_timeout_ms);
+        let ret = match rdr.fill_buf() {
+            Ok(buf) => !buf.is_empty(),
+            Err(ref e) if e.kind() == io::ErrorKind::TimedOut => {
+                debug!("keep-alive connection {} idle for too long", addr);
+                self.stats.idle_timeouts.fetch_add(1, Ordering::Relaxed);
+                false
+            },
+            Err(e) => {
+                debug!("ioerror waiting for the next request = {:?}", e);
+                false
+            }
+        };
+        limit.clear();
+        ret
+    }
+
+    fn handle_expect<W: Write>(&self, req: &Request, wrt: &mut W) -> bool {
+         if req.version == HttpVersion::Http11 && req.headers.get() == Some(&Expect::Continue) {
+            let status = self.handler.check_continue((&req.method, &req.uri, &req.headers));
+            match write!(wrt, "{} {}\r\n\r\n", HttpVersion::Http11, status) {
+                Ok(..) => (),
+                Err(e) => {
+                    error!("error writing 100-continue: {:?}", e);
+                    return false;
+                }
+            }
+
+            if status != StatusCode::Continue {
+                debug!("non-100 status ({}) for Expect 100 request", status);
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+/// Length of `HTTP/1.1 200`, the start of a status line up to the code
+const STATUS_LINE_START: usize = 12;
+
+/// Writes the response of a handler, recording its status
+///
+/// The body of responses to HEAD requests is discarded, so handlers for GET can answer them too.
+struct ResponseWriter<W: Write> {
+    inner: W,
+    /// The first `STATUS_LINE_START` bytes of the response
+    start: Vec<u8>,
+    head_only: bool,
+    /// Line breaks in a row at the end of what was written of the head, 2 once it is complete
+    newlines: u8,
+}
+
+impl<W: Write> ResponseWriter<W> {
+    fn new(inner: W, head_only: bool) -> ResponseWriter<W> {
+        ResponseWriter {
+            inner: inner,
+            start: Vec::with_capacity(STATUS_LINE_START),
+            head_only: head_only,
+            newlines: 0,
+        }
+    }
+
+    /// Status the response was sent with, `None` if no complete status line was written
+    fn status(&self) -> Option<StatusCode> {
+        if self.start.len() < STATUS_LINE_START {
+            return None;
+        }
+        str::from_utf8(&self.start[9..STATUS_LINE_START]).ok()
+            .and_then(|code| code.parse().ok())
+            .map(StatusCode::from_u16)
+    }
+}
+
+impl<W: Write> Write for ResponseWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = if !self.head_only {
+            try!(self.inner.write(buf))
+        } else if self.newlines == 2 {
+            // Discard the body
+            buf.len()
+        } else {
+            let mut newlines = self.newlines;
+            let head = find_header_end(&mut newlines, buf).unwrap_or(buf.len());
+            try!(self.inner.write_all(&buf[..head]));
+            self.newlines = newlines;
+            buf.len()
+        };
+
+        if self.start.len() < STATUS_LINE_START {
+            let start = cmp::min(STATUS_LINE_START - self.start.len(), len);
+            self.start.extend(buf[..start].iter().cloned());
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Status of the response to a request which could not be parsed
+fn error_status(err: &Error) -> StatusCode {
+    match *err {
+        Error::Version => StatusCode::HttpVersionNotSupported,
+        Error::TooLarge => StatusCode::RequestHeaderFieldsTooLarge,
+        _ => StatusCode::BadRequest,
+    }
+}
+
+/// Writes an empty response which closes the connection, so the client can tell it from a reset
+fn send_error<W: Write>(wrt: &mut W, status: StatusCode) {
+    let result = write!(wrt, "{} {}\r\nConnection: close\r\nContent-Length: 0\r\n\r\n",
+                        HttpVersion::Http11, status)
+                     .and_then(|_| wrt.flush());
+    if let Err(e) = result {
+        debug!("error writing {} response: {:?}", status, e);
+    }
+}
+
+/// Closes the connection after a rejected request whose rest the client may still be sending
+///
+/// Closing with unread data makes the kernel reset the connection, which can discard the error
+/// response before the client read it. So the write side is shut down first, and the rest of the
+/// request is discarded until the client closes too, for at most `LINGER_TIMEOUT_MS` and
+/// `LINGER_MAX_BYTES`.
+fn close_lingering<W: Write>(rdr: &mut BufReader<&mut NetworkStream>, wrt: &mut W, limit: &ReadLimit) {
+    if wrt.flush().is_err() || rdr.get_mut().close(Shutdown::Write).is_err() {
+        return;
+    }
+
+    limit.clear();
+    limit.set_deadline(Some(LINGER_TIMEOUT_MS));
+    limit.set_max_bytes(Some(LINGER_MAX_BYTES));
+    let mut buf = [0u8; 4096];
+    loop {
+        match rdr.read(&mut buf) {
+            Ok(0) | Err(..) => break,
+            Ok(..) => (),
+        }
+    }
+    limit.clear();
+}
+
+/// Replaces the client certificate headers of a request with the identity from the TLS handshake
+///
+/// Headers with these names sent by the client are always removed, so handlers can trust them.
+fn set_identity_headers(headers: &mut Headers, identity: Option<&PeerIdentity>) {
+    headers.remove_raw(CLIENT_SUBJECT_HEADER);
+    headers.remove_raw(CLIENT_SAN_HEADER);
+
+    if let Some(identity) = identity {
+        if !identity.subject.is_empty() {
+            headers.set_raw(CLIENT_SUBJECT_HEADER, vec![identity.subject.as_bytes().to_vec()]);
+        }
+        if !identity.alt_names.is_empty() {
+            headers.set_raw(CLIENT_SAN_HEADER, vec![identity.alt_names.join(", ").into_bytes()]);
+        }
+    }
+}
@@ -0,0 +1,81 @@
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use libc::{self, c_void, size_t};
+
+/// An owned file descriptor, closed when dropped
+///
+/// Reads and writes are plain `read(2)` and `write(2)` calls. Wrap it in an `Async` to park the
+/// coroutine instead of blocking the thread.
+pub struct FileDesc(RawFd);
+
+impl FileDesc {
+    /// Takes the ownership of `fd`
+    pub fn new(fd: RawFd) -> FileDesc {
+        FileDesc(fd)
+    }
+
+    /// Duplicates `fd` into a new close-on-exec file descriptor sharing the same open file
+    pub fn dup(fd: RawFd) -> io::Result<FileDesc> {
+        let new_fd = unsafe { libc::dup(fd) };
+        if new_fd == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let fd = FileDesc(new_fd);
+        try!(fd.set_cloexec());
+        Ok(fd)
+    }
+
+    pub fn set_cloexec(&self) -> io::Result<()> {
+        if unsafe { libc::fcntl(self.0, libc::F_SETFD, libc::FD_CLOEXEC) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Gives up the ownership of the file descriptor without closing it
+    pub fn into_raw_fd(self) -> RawFd {
+        let fd = self.0;
+        ::std::mem::forget(self);
+        fd
+    }
+}
+
+impl io::Read for FileDesc {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let ret = unsafe { libc::read(self.0, buf.as_mut_ptr() as *mut c_void, buf.len() as size_t) };
+        if ret == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(ret as usize)
+    }
+}
+
+impl io::Write for FileDesc {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let ret = unsafe { libc::write(self.0, buf.as_ptr() as *const c_void, buf.len() as size_t) };
+        if ret == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(ret as usize)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AsRawFd for FileDesc {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+
+impl Drop for FileDesc {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.0);
+        }
+    }
+}
@@ -0,0 +1,131 @@
+use std::io;
+use std::mem;
+use std::ptr;
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use libc;
+
+use mio::{Interest, Io};
+
+use scheduler::retry_nonblocking;
+
+pub use self::fd::FileDesc;
+pub use self::pipe::{pipe, PipeReader, PipeWriter};
+pub use self::stdio::{stdin, stdout, stderr, Stdin, Stdout, Stderr};
+
+mod fd;
+mod pipe;
+mod stdio;
+
+/// Makes blocking-style I/O on any file descriptor park the current coroutine instead of the thread
+///
+/// The file descriptor is put into non-blocking mode. `read_with` and `write_with` run an I/O
+/// operation and, as long as it fails with `WouldBlock`, wait for the descriptor to become
+/// readable or writable in the event loop before trying again.
+pub struct Async<T: AsRawFd> {
+    inner: T,
+    // Registers the fd of `inner` into the event loop. Never closes it, `inner` does.
+    io: Option<Io>,
+}
+
+impl<T: AsRawFd> Async<T> {
+    pub fn new(inner: T) -> io::Result<Async<T>> {
+        let fd = inner.as_raw_fd();
+        try!(set_nonblocking(fd));
+
+        Ok(Async {
+            inner: inner,
+            io: Some(Io::from(fd)),
+        })
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    pub fn into_inner(mut self) -> T {
+        mem::forget(self.io.take());
+        unsafe {
+            let inner = ptr::read(&self.inner);
+            mem::forget(self);
+            inner
+        }
+    }
+
+    /// Calls `f` until it does not fail with `WouldBlock`, waiting for the fd to be readable in between
+    pub fn read_with<F, R>(&self, mut f: F) -> io::Result<R>
+            where F: FnMut(&T) -> io::Result<R> {
+        let inner = &self.inner;
+        retry_nonblocking(self.io(), Interest::readable(), || f(inner))
+    }
+
+    /// Same as `read_with`, with mutable access to the inner I/O object
+    pub fn read_with_mut<F, R>(&mut self, mut f: F) -> io::Result<R>
+            where F: FnMut(&mut T) -> io::Result<R> {
+        let io = self.io.as_ref().unwrap();
+        let inner = &mut self.inner;
+        retry_nonblocking(io, Interest::readable(), || f(inner))
+    }
+
+    /// Calls `f` until it does not fail with `WouldBlock`, waiting for the fd to be writable in between
+    pub fn write_with<F, R>(&self, mut f: F) -> io::Result<R>
+            where F: FnMut(&T) -> io::Result<R> {
+        let inner = &self.inner;
+        retry_nonblocking(self.io(), Interest::writable(), || f(inner))
+    }
+
+    /// Same as `write_with`, with mutable access to the inner I/O object
+    pub fn write_with_mut<F, R>(&mut self, mut f: F) -> io::Result<R>
+            where F: FnMut(&mut T) -> io::Result<R> {
+        let io = self.io.as_ref().unwrap();
+        let inner = &mut self.inner;
+        retry_nonblocking(io, Interest::writable(), || f(inner))
+    }
+
+    fn io(&self) -> &Io {
+        self.io.as_ref().unwrap()
+    }
+}
+
+impl<T: AsRawFd> Drop for Async<T> {
+    fn drop(&mut self) {
+        // The fd belongs to `inner`
+        mem::forget(self.io.take());
+    }
+}
+
+impl<T: AsRawFd> AsRawFd for Async<T> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+}
+
+impl<T: AsRawFd + io::Read> io::Read for Async<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.read_with_mut(|inner| inner.read(buf))
+    }
+}
+
+impl<T: AsRawFd + io::Write> io::Write for Async<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_with_mut(|inner| inner.write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_with_mut(|inner| inner.flush())
+    }
+}
+
+fn set_nonblocking(fd: RawFd) -> io::Result<()> {
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFL);
+        if flags == -1 || libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,108 @@
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use libc;
+
+use super::{Async, FileDesc};
+
+/// Read end of a pipe created by `pipe`
+pub struct PipeReader(Async<FileDesc>);
+
+/// Write end of a pipe created by `pipe`
+pub struct PipeWriter(Async<FileDesc>);
+
+/// Creates a non-blocking, close-on-exec pipe whose reads and writes park the current coroutine
+pub fn pipe() -> io::Result<(PipeReader, PipeWriter)> {
+    let (reader, writer) = try!(raw_pipe());
+    Ok((PipeReader(try!(Async::new(reader))), PipeWriter(try!(Async::new(writer)))))
+}
+
+#[cfg(any(target_os = "linux",
+          target_os = "android"))]
+const O_CLOEXEC: libc::c_int = 0o2000000;
+
+#[cfg(any(target_os = "linux",
+          target_os = "android"))]
+extern {
+    fn pipe2(fds: *mut libc::c_int, flags: libc::c_int) -> libc::c_int;
+}
+
+// Both ends are close-on-exec from the start, so a concurrent fork/exec cannot inherit them
+#[cfg(any(target_os = "linux",
+          target_os = "android"))]
+fn raw_pipe() -> io::Result<(FileDesc, FileDesc)> {
+    let mut fds = [0; 2];
+    if unsafe { pipe2(fds.as_mut_ptr(), O_CLOEXEC | libc::O_NONBLOCK) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok((FileDesc::new(fds[0]), FileDesc::new(fds[1])))
+}
+
+// Without `pipe2` there is a window in which a concurrent fork/exec inherits both ends
+#[cfg(any(target_os = "macos",
+          target_os = "freebsd",
+          target_os = "dragonfly",
+          target_os = "ios",
+          target_os = "bitrig",
+          target_os = "openbsd"))]
+fn raw_pipe() -> io::Result<(FileDesc, FileDesc)> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let reader = FileDesc::new(fds[0]);
+    let writer = FileDesc::new(fds[1]);
+    try!(reader.set_cloexec());
+    try!(writer.set_cloexec());
+    Ok((reader, writer))
+}
+
+impl PipeReader {
+    pub fn from_fd(fd: FileDesc) -> io::Result<PipeReader> {
+        Ok(PipeReader(try!(Async::new(fd))))
+    }
+
+    pub fn into_fd(self) -> FileDesc {
+        self.0.into_inner()
+    }
+}
+
+impl PipeWriter {
+    pub fn from_fd(fd: FileDesc) -> io::Result<PipeWriter> {
+        Ok(PipeWriter(try!(Async::new(fd))))
+    }
+
+    pub fn into_fd(self) -> FileDesc {
+        self.0.into_inner()
+    }
+}
+
+impl io::Read for PipeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl io::Write for PipeWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl AsRawFd for PipeReader {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
+impl AsRawFd for PipeWriter {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
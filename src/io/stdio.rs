@@ -0,0 +1,136 @@
+use std::cmp;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use libc;
+
+use mio::{Interest, Io};
+
+use scheduler::Scheduler;
+
+use super::FileDesc;
+
+/// Largest write issued at once, a pipe reports writable as soon as this much fits
+const MAX_WRITE: usize = 4096;
+
+// A duplicate of one of the standard streams
+//
+// The descriptor stays blocking. Its open file description is usually shared with the other
+// standard streams, the shell and the rest of a pipeline, which all expect blocking I/O. Instead
+// the coroutine waits until it is readable or writable, and then issues a single read or write,
+// which does not block anymore.
+struct StdStream {
+    fd: FileDesc,
+    // Registers `fd` into the event loop. Never closes it, `fd` does.
+    io: Option<Io>,
+}
+
+impl StdStream {
+    fn new(fd: RawFd) -> io::Result<StdStream> {
+        let fd = try!(FileDesc::dup(fd));
+        let io = Io::from(fd.as_raw_fd());
+        Ok(StdStream {
+            fd: fd,
+            io: Some(io),
+        })
+    }
+
+    fn wait(&self, interest: Interest) -> io::Result<()> {
+        match Scheduler::current().wait_event(self.io.as_ref().unwrap(), interest) {
+            // Regular files cannot be registered into the event loop, but never block either
+            Err(ref err) if err.raw_os_error() == Some(libc::EPERM) => Ok(()),
+            result => result,
+        }
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        try!(self.wait(Interest::readable()));
+        self.fd.read(buf)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        try!(self.wait(Interest::writable()));
+        let len = cmp::min(buf.len(), MAX_WRITE);
+        self.fd.write(&buf[..len])
+    }
+}
+
+impl Drop for StdStream {
+    fn drop(&mut self) {
+        // The fd belongs to `fd`
+        mem::forget(self.io.take());
+    }
+}
+
+/// The standard input of the process, whose reads park the current coroutine
+pub struct Stdin(StdStream);
+
+/// The standard output of the process, whose writes park the current coroutine
+pub struct Stdout(StdStream);
+
+/// The standard error of the process, whose writes park the current coroutine
+pub struct Stderr(StdStream);
+
+/// Returns a handle to the standard input
+///
+/// It is not buffered, wrap it in a `BufReader` to read lines.
+pub fn stdin() -> io::Result<Stdin> {
+    Ok(Stdin(try!(StdStream::new(libc::STDIN_FILENO))))
+}
+
+/// Returns a handle to the standard output
+///
+/// It is not buffered and does not share the buffer of `std::io::stdout`.
+pub fn stdout() -> io::Result<Stdout> {
+    Ok(Stdout(try!(StdStream::new(libc::STDOUT_FILENO))))
+}
+
+/// Returns a handle to the standard error
+pub fn stderr() -> io::Result<Stderr> {
+    Ok(Stderr(try!(StdStream::new(libc::STDERR_FILENO))))
+}
+
+impl io::Read for Stdin {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl io::Write for Stdout {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.fd.flush()
+    }
+}
+
+impl io::Write for Stderr {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.fd.flush()
+    }
+}
+
+impl AsRawFd for Stdin {
+    fn as_raw_fd(&self) -> RawFd {
+        (self.0).fd.as_raw_fd()
+    }
+}
+
+impl AsRawFd for Stdout {
+    fn as_raw_fd(&self) -> RawFd {
+        (self.0).fd.as_raw_fd()
+    }
+}
+
+impl AsRawFd for Stderr {
+    fn as_raw_fd(&self) -> RawFd {
+        (self.0).fd.as_raw_fd()
+    }
+}
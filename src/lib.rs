@@ -8,6 +8,14 @@ extern crate env_logger;
 extern crate mio;
 extern crate libc;
 extern crate rand;
+extern crate openssl;
+extern crate hyper;
+extern crate time;
 
 pub mod scheduler;
 pub mod net;
+pub mod io;
+pub mod sync;
+pub mod process;
+pub mod signal;
+pub mod http;
@@ -0,0 +1,79 @@
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::sync::{Arc, Mutex};
+
+use scheduler::Scheduler;
+use sync::Notify;
+
+use super::tcp::TcpStream;
+
+/// A stream which can be read and written from two coroutines at once, and half-closed
+pub trait Duplex: Read + Write + Send + Sized + 'static {
+    /// Creates a new handle to the same underlying stream
+    fn try_clone(&self) -> io::Result<Self>;
+
+    /// Shuts down the read, write, or both halves of the stream
+    fn shutdown(&self, how: Shutdown) -> io::Result<()>;
+}
+
+impl Duplex for TcpStream {
+    fn try_clone(&self) -> io::Result<TcpStream> {
+        TcpStream::try_clone(self)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        TcpStream::shutdown(self, how)
+    }
+}
+
+/// Copies data in both directions between `a` and `b` until both of them reach EOF
+///
+/// The `b -> a` direction runs in a new coroutine. When one side reaches EOF, the write half of
+/// the other side is shut down, so half-closed connections are proxied correctly. If one
+/// direction fails, both streams are shut down to stop the other one.
+///
+/// TLS connections are proxied by wrapping them in a `SharedTlsStream`.
+///
+/// Returns the number of bytes copied from `a` to `b`, and from `b` to `a`.
+pub fn copy_bidirectional<A: Duplex, B: Duplex>(a: A, b: B) -> io::Result<(u64, u64)> {
+    let a_clone = try!(a.try_clone());
+    let b_clone = try!(b.try_clone());
+
+    let b_to_a = Arc::new(Mutex::new(None));
+    let done = Notify::new();
+
+    {
+        let b_to_a = b_to_a.clone();
+        let done = done.clone();
+
+        Scheduler::spawn(move|| {
+            let result = copy_half(b_clone, a_clone);
+            *b_to_a.lock().unwrap() = Some(result);
+            done.notify();
+        });
+    }
+
+    let a_to_b = copy_half(a, b);
+
+    done.wait();
+    let b_to_a = b_to_a.lock().unwrap().take().unwrap();
+
+    Ok((try!(a_to_b), try!(b_to_a)))
+}
+
+fn copy_half<R: Duplex, W: Duplex>(mut from: R, mut to: W) -> io::Result<u64> {
+    let result = io::copy(&mut from, &mut to).and_then(|len| {
+        debug!("copy_half: EOF after {} bytes; shutting down the write half", len);
+        try!(to.flush());
+        try!(to.shutdown(Shutdown::Write));
+        Ok(len)
+    });
+
+    if let Err(ref err) = result {
+        debug!("copy_half: error {:?}; shutting down both streams", err);
+        let _ = from.shutdown(Shutdown::Both);
+        let _ = to.shutdown(Shutdown::Both);
+    }
+
+    result
+}
@@ -1,27 +1,153 @@
 
 pub use self::tcp::{TcpListener, TcpStream, TcpSocket};
+#[cfg(any(target_os = "linux",
+          target_os = "android"))]
+pub use self::tcp::SplicePipe;
 pub use self::udp::UdpSocket;
+pub use self::unix::{UnixListener, UnixStream, UnixDatagram, UnixAddr};
+pub use self::tls::{TlsAcceptor, ReloadableAcceptor, TlsConnector, TlsStream, SharedTlsStream,
+                    PeerIdentity};
+pub use self::copy::{copy_bidirectional, Duplex};
 
 use std::io;
 use std::net::{ToSocketAddrs, SocketAddr};
 
-macro_rules! try_wouldblock(
-    ($e:expr) => {{
-        match $e {
-            Ok(None) => (),
-            Ok(Some(s)) => {
-                return Ok(s);
-            },
-            Err(e) => {
-                error!("try_nonblock: {}", e);
-                return Err(e);
+/// Implements typed getters and setters of the generic socket options for a type with `AsRawFd`
+macro_rules! impl_socket_opts(
+    ($t:ty) => {
+        impl $t {
+            /// Sets the size of the kernel receive buffer (`SO_RCVBUF`)
+            pub fn set_recv_buffer_size(&self, size: usize) -> io::Result<()> {
+                ::net::sockopt::set_recv_buffer_size(self.as_raw_fd(), size)
+            }
+
+            /// Gets the size of the kernel receive buffer (`SO_RCVBUF`)
+            pub fn recv_buffer_size(&self) -> io::Result<usize> {
+                ::net::sockopt::recv_buffer_size(self.as_raw_fd())
+            }
+
+            /// Sets the size of the kernel send buffer (`SO_SNDBUF`)
+            pub fn set_send_buffer_size(&self, size: usize) -> io::Result<()> {
+                ::net::sockopt::set_send_buffer_size(self.as_raw_fd(), size)
+            }
+
+            /// Gets the size of the kernel send buffer (`SO_SNDBUF`)
+            pub fn send_buffer_size(&self) -> io::Result<usize> {
+                ::net::sockopt::send_buffer_size(self.as_raw_fd())
+            }
+
+            /// Sets the time-to-live of outgoing IPv4 packets (`IP_TTL`)
+            pub fn set_ttl(&self, ttl: u32) -> io::Result<()> {
+                ::net::sockopt::set_ttl(self.as_raw_fd(), ttl)
+            }
+
+            /// Gets the time-to-live of outgoing IPv4 packets (`IP_TTL`)
+            pub fn ttl(&self) -> io::Result<u32> {
+                ::net::sockopt::ttl(self.as_raw_fd())
+            }
+
+            /// Restricts an IPv6 socket to IPv6 traffic only (`IPV6_V6ONLY`). Must be set before binding.
+            pub fn set_only_v6(&self, only_v6: bool) -> io::Result<()> {
+                ::net::sockopt::set_only_v6(self.as_raw_fd(), only_v6)
+            }
+
+            /// Gets the value of `IPV6_V6ONLY`
+            pub fn only_v6(&self) -> io::Result<bool> {
+                ::net::sockopt::only_v6(self.as_raw_fd())
+            }
+
+            /// Allows binding to an address in `TIME_WAIT` (`SO_REUSEADDR`). Must be set before binding.
+            pub fn set_reuseaddr(&self, reuse: bool) -> io::Result<()> {
+                ::net::sockopt::set_reuseaddr(self.as_raw_fd(), reuse)
+            }
+
+            /// Gets the value of `SO_REUSEADDR`
+            pub fn reuseaddr(&self) -> io::Result<bool> {
+                ::net::sockopt::reuseaddr(self.as_raw_fd())
+            }
+
+            /// Allows several sockets to bind to the same address (`SO_REUSEPORT`). Must be set before binding.
+            pub fn set_reuseport(&self, reuse: bool) -> io::Result<()> {
+                ::net::sockopt::set_reuseport(self.as_raw_fd(), reuse)
+            }
+
+            /// Gets the value of `SO_REUSEPORT`
+            pub fn reuseport(&self) -> io::Result<bool> {
+                ::net::sockopt::reuseport(self.as_raw_fd())
             }
         }
-    }};
+    };
+);
+
+/// Implements typed getters and setters of the TCP specific socket options for a type with `AsRawFd`
+macro_rules! impl_tcp_opts(
+    ($t:ty) => {
+        impl_socket_opts!($t);
+
+        impl $t {
+            /// Disables the Nagle algorithm (`TCP_NODELAY`)
+            pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+                ::net::sockopt::set_nodelay(self.as_raw_fd(), nodelay)
+            }
+
+            /// Gets the value of `TCP_NODELAY`
+            pub fn nodelay(&self) -> io::Result<bool> {
+                ::net::sockopt::nodelay(self.as_raw_fd())
+            }
+
+            /// Enables `SO_KEEPALIVE` with the given idle time in seconds before the first probe,
+            /// or disables it with `None`
+            pub fn set_keepalive(&self, idle_secs: Option<u32>) -> io::Result<()> {
+                ::net::sockopt::set_keepalive(self.as_raw_fd(), idle_secs)
+            }
+
+            /// Gets the keepalive idle time in seconds, or `None` if `SO_KEEPALIVE` is disabled
+            pub fn keepalive(&self) -> io::Result<Option<u32>> {
+                ::net::sockopt::keepalive(self.as_raw_fd())
+            }
+
+            /// Sets the interval in seconds between two keepalive probes (`TCP_KEEPINTVL`)
+            pub fn set_keepalive_interval(&self, secs: u32) -> io::Result<()> {
+                ::net::sockopt::set_keepalive_interval(self.as_raw_fd(), secs)
+            }
+
+            /// Gets the interval in seconds between two keepalive probes (`TCP_KEEPINTVL`)
+            pub fn keepalive_interval(&self) -> io::Result<u32> {
+                ::net::sockopt::keepalive_interval(self.as_raw_fd())
+            }
+
+            /// Sets the number of unanswered probes before the connection is dropped (`TCP_KEEPCNT`)
+            pub fn set_keepalive_count(&self, count: u32) -> io::Result<()> {
+                ::net::sockopt::set_keepalive_count(self.as_raw_fd(), count)
+            }
+
+            /// Gets the number of unanswered probes before the connection is dropped (`TCP_KEEPCNT`)
+            pub fn keepalive_count(&self) -> io::Result<u32> {
+                ::net::sockopt::keepalive_count(self.as_raw_fd())
+            }
+
+            /// Sets `SO_LINGER` to the given number of seconds, or disables it with `None`
+            pub fn set_linger(&self, secs: Option<u32>) -> io::Result<()> {
+                ::net::sockopt::set_linger(self.as_raw_fd(), secs)
+            }
+
+            /// Gets the `SO_LINGER` timeout in seconds, or `None` if it is disabled
+            pub fn linger(&self) -> io::Result<Option<u32>> {
+                ::net::sockopt::linger(self.as_raw_fd())
+            }
+        }
+    };
 );
 
 pub mod tcp;
 pub mod udp;
+pub mod unix;
+pub mod tls;
+
+mod copy;
+
+mod sockopt;
+mod sys;
 
 fn each_addr<A: ToSocketAddrs, F, T>(addr: A, mut f: F) -> io::Result<T>
     where F: FnMut(&SocketAddr) -> io::Result<T>
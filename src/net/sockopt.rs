@@ -0,0 +1,381 @@
+use std::io;
+use std::mem;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use libc::{self, c_int, c_uint, c_void, socklen_t};
+
+pub fn setsockopt<T>(fd: RawFd, level: c_int, name: c_int, val: T) -> io::Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(fd, level, name,
+                         &val as *const T as *const c_void,
+                         mem::size_of::<T>() as socklen_t)
+    };
+
+    if ret == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+pub fn getsockopt<T: Copy>(fd: RawFd, level: c_int, name: c_int) -> io::Result<T> {
+    unsafe {
+        let mut slot: T = mem::zeroed();
+        let mut len = mem::size_of::<T>() as socklen_t;
+        let ret = libc::getsockopt(fd, level, name,
+                                   &mut slot as *mut T as *mut c_void,
+                                   &mut len);
+
+        if ret == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        assert_eq!(len as usize, mem::size_of::<T>());
+        Ok(slot)
+    }
+}
+
+/// Reads and clears the pending error on the socket (`SO_ERROR`)
+pub fn take_error<S: AsRawFd>(sock: &S) -> io::Result<Option<io::Error>> {
+    let raw: c_int = try!(getsockopt(sock.as_raw_fd(), libc::SOL_SOCKET, libc::SO_ERROR));
+    if raw == 0 {
+        Ok(None)
+    } else {
+        Ok(Some(io::Error::from_raw_os_error(raw)))
+    }
+}
+
+#[cfg(any(target_os = "linux",
+          target_os = "android"))]
+mod consts {
+    use libc::c_int;
+
+    pub const SO_REUSEADDR: c_int = 2;
+    pub const SO_BROADCAST: c_int = 6;
+    pub const SO_SNDBUF: c_int = 7;
+    pub const SO_RCVBUF: c_int = 8;
+    pub const SO_KEEPALIVE: c_int = 9;
+    pub const SO_LINGER: c_int = 13;
+    pub const SO_REUSEPORT: c_int = 15;
+
+    pub const TCP_NODELAY: c_int = 1;
+    pub const TCP_KEEPIDLE: Option<c_int> = Some(4);
+    pub const TCP_KEEPINTVL: Option<c_int> = Some(5);
+    pub const TCP_KEEPCNT: Option<c_int> = Some(6);
+
+    pub const IP_TTL: c_int = 2;
+    pub const IP_MULTICAST_IF: c_int = 32;
+    pub const IP_MULTICAST_TTL: c_int = 33;
+    pub const IP_MULTICAST_LOOP: c_int = 34;
+    pub const IP_ADD_MEMBERSHIP: c_int = 35;
+    pub const IP_DROP_MEMBERSHIP: c_int = 36;
+
+    pub const IPV6_MULTICAST_HOPS: c_int = 18;
+    pub const IPV6_MULTICAST_LOOP: c_int = 19;
+    pub const IPV6_JOIN_GROUP: c_int = 20;
+    pub const IPV6_LEAVE_GROUP: c_int = 21;
+    pub const IPV6_V6ONLY: c_int = 26;
+
+    // Linux accepts an int for the IPv4 multicast options
+    pub type McastV4Opt = c_int;
+}
+
+#[cfg(any(target_os = "macos",
+          target_os = "freebsd",
+          target_os = "dragonfly",
+          target_os = "ios",
+          target_os = "bitrig",
+          target_os = "openbsd"))]
+mod consts {
+    use libc::c_int;
+
+    pub const SO_REUSEADDR: c_int = 0x0004;
+    pub const SO_KEEPALIVE: c_int = 0x0008;
+    pub const SO_BROADCAST: c_int = 0x0020;
+    pub const SO_LINGER: c_int = 0x0080;
+    pub const SO_REUSEPORT: c_int = 0x0200;
+    pub const SO_SNDBUF: c_int = 0x1001;
+    pub const SO_RCVBUF: c_int = 0x1002;
+
+    pub const TCP_NODELAY: c_int = 0x01;
+
+    // The keepalive tuning options differ between the BSDs, OpenBSD has none of them
+    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    pub const TCP_KEEPIDLE: Option<c_int> = Some(0x10); // TCP_KEEPALIVE
+    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    pub const TCP_KEEPINTVL: Option<c_int> = Some(0x101);
+    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    pub const TCP_KEEPCNT: Option<c_int> = Some(0x102);
+
+    #[cfg(any(target_os = "freebsd", target_os = "dragonfly"))]
+    pub const TCP_KEEPIDLE: Option<c_int> = Some(0x100);
+    #[cfg(any(target_os = "freebsd", target_os = "dragonfly"))]
+    pub const TCP_KEEPINTVL: Option<c_int> = Some(0x200);
+    #[cfg(any(target_os = "freebsd", target_os = "dragonfly"))]
+    pub const TCP_KEEPCNT: Option<c_int> = Some(0x400);
+
+    #[cfg(any(target_os = "bitrig", target_os = "openbsd"))]
+    pub const TCP_KEEPIDLE: Option<c_int> = None;
+    #[cfg(any(target_os = "bitrig", target_os = "openbsd"))]
+    pub const TCP_KEEPINTVL: Option<c_int> = None;
+    #[cfg(any(target_os = "bitrig", target_os = "openbsd"))]
+    pub const TCP_KEEPCNT: Option<c_int> = None;
+
+    pub const IP_TTL: c_int = 4;
+    pub const IP_MULTICAST_IF: c_int = 9;
+    pub const IP_MULTICAST_TTL: c_int = 10;
+    pub const IP_MULTICAST_LOOP: c_int = 11;
+    pub const IP_ADD_MEMBERSHIP: c_int = 12;
+    pub const IP_DROP_MEMBERSHIP: c_int = 13;
+
+    pub const IPV6_MULTICAST_HOPS: c_int = 10;
+    pub const IPV6_MULTICAST_LOOP: c_int = 11;
+    pub const IPV6_JOIN_GROUP: c_int = 12;
+    pub const IPV6_LEAVE_GROUP: c_int = 13;
+    pub const IPV6_V6ONLY: c_int = 27;
+
+    // BSDs only accept an u_char for the IPv4 multicast options
+    pub type McastV4Opt = u8;
+}
+
+pub use self::consts::*;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Linger {
+    l_onoff: c_int,
+    l_linger: c_int,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct IpMreq {
+    imr_multiaddr: [u8; 4],
+    imr_interface: [u8; 4],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Ipv6Mreq {
+    ipv6mr_multiaddr: [u8; 16],
+    ipv6mr_interface: c_uint,
+}
+
+fn ipv6_octets(addr: &Ipv6Addr) -> [u8; 16] {
+    let mut octets = [0; 16];
+    for (idx, seg) in addr.segments().iter().enumerate() {
+        octets[idx * 2] = (*seg >> 8) as u8;
+        octets[idx * 2 + 1] = *seg as u8;
+    }
+    octets
+}
+
+fn set_bool(fd: RawFd, level: c_int, name: c_int, val: bool) -> io::Result<()> {
+    setsockopt(fd, level, name, val as c_int)
+}
+
+fn get_bool(fd: RawFd, level: c_int, name: c_int) -> io::Result<bool> {
+    let raw: c_int = try!(getsockopt(fd, level, name));
+    Ok(raw != 0)
+}
+
+fn set_u32(fd: RawFd, level: c_int, name: c_int, val: u32) -> io::Result<()> {
+    setsockopt(fd, level, name, val as c_int)
+}
+
+fn get_u32(fd: RawFd, level: c_int, name: c_int) -> io::Result<u32> {
+    let raw: c_int = try!(getsockopt(fd, level, name));
+    Ok(raw as u32)
+}
+
+/// Unwraps a keepalive tuning option, which some platforms do not have
+fn keepalive_opt(name: Option<c_int>) -> io::Result<c_int> {
+    name.ok_or_else(|| io::Error::new(io::ErrorKind::Other,
+                                      "TCP keepalive tuning is not supported on this platform"))
+}
+
+pub fn set_nodelay(fd: RawFd, nodelay: bool) -> io::Result<()> {
+    set_bool(fd, libc::IPPROTO_TCP, TCP_NODELAY, nodelay)
+}
+
+pub fn nodelay(fd: RawFd) -> io::Result<bool> {
+    get_bool(fd, libc::IPPROTO_TCP, TCP_NODELAY)
+}
+
+pub fn set_keepalive(fd: RawFd, idle_secs: Option<u32>) -> io::Result<()> {
+    match idle_secs {
+        Some(secs) => {
+            let idle = try!(keepalive_opt(TCP_KEEPIDLE));
+            try!(set_bool(fd, libc::SOL_SOCKET, SO_KEEPALIVE, true));
+            set_u32(fd, libc::IPPROTO_TCP, idle, secs)
+        },
+        None => set_bool(fd, libc::SOL_SOCKET, SO_KEEPALIVE, false),
+    }
+}
+
+pub fn keepalive(fd: RawFd) -> io::Result<Option<u32>> {
+    if !try!(get_bool(fd, libc::SOL_SOCKET, SO_KEEPALIVE)) {
+        return Ok(None);
+    }
+    get_u32(fd, libc::IPPROTO_TCP, try!(keepalive_opt(TCP_KEEPIDLE))).map(Some)
+}
+
+pub fn set_keepalive_interval(fd: RawFd, secs: u32) -> io::Result<()> {
+    set_u32(fd, libc::IPPROTO_TCP, try!(keepalive_opt(TCP_KEEPINTVL)), secs)
+}
+
+pub fn keepalive_interval(fd: RawFd) -> io::Result<u32> {
+    get_u32(fd, libc::IPPROTO_TCP, try!(keepalive_opt(TCP_KEEPINTVL)))
+}
+
+pub fn set_keepalive_count(fd: RawFd, count: u32) -> io::Result<()> {
+    set_u32(fd, libc::IPPROTO_TCP, try!(keepalive_opt(TCP_KEEPCNT)), count)
+}
+
+pub fn keepalive_count(fd: RawFd) -> io::Result<u32> {
+    get_u32(fd, libc::IPPROTO_TCP, try!(keepalive_opt(TCP_KEEPCNT)))
+}
+
+pub fn set_recv_buffer_size(fd: RawFd, size: usize) -> io::Result<()> {
+    setsockopt(fd, libc::SOL_SOCKET, SO_RCVBUF, size as c_int)
+}
+
+pub fn recv_buffer_size(fd: RawFd) -> io::Result<usize> {
+    let raw: c_int = try!(getsockopt(fd, libc::SOL_SOCKET, SO_RCVBUF));
+    Ok(raw as usize)
+}
+
+pub fn set_send_buffer_size(fd: RawFd, size: usize) -> io::Result<()> {
+    setsockopt(fd, libc::SOL_SOCKET, SO_SNDBUF, size as c_int)
+}
+
+pub fn send_buffer_size(fd: RawFd) -> io::Result<usize> {
+    let raw: c_int = try!(getsockopt(fd, libc::SOL_SOCKET, SO_SNDBUF));
+    Ok(raw as usize)
+}
+
+pub fn set_linger(fd: RawFd, secs: Option<u32>) -> io::Result<()> {
+    let val = Linger {
+        l_onoff: secs.is_some() as c_int,
+        l_linger: secs.unwrap_or(0) as c_int,
+    };
+    setsockopt(fd, libc::SOL_SOCKET, SO_LINGER, val)
+}
+
+pub fn linger(fd: RawFd) -> io::Result<Option<u32>> {
+    let val: Linger = try!(getsockopt(fd, libc::SOL_SOCKET, SO_LINGER));
+    if val.l_onoff == 0 {
+        Ok(None)
+    } else {
+        Ok(Some(val.l_linger as u32))
+    }
+}
+
+pub fn set_ttl(fd: RawFd, ttl: u32) -> io::Result<()> {
+    set_u32(fd, libc::IPPROTO_IP, IP_TTL, ttl)
+}
+
+pub fn ttl(fd: RawFd) -> io::Result<u32> {
+    get_u32(fd, libc::IPPROTO_IP, IP_TTL)
+}
+
+pub fn set_only_v6(fd: RawFd, only_v6: bool) -> io::Result<()> {
+    set_bool(fd, libc::IPPROTO_IPV6, IPV6_V6ONLY, only_v6)
+}
+
+pub fn only_v6(fd: RawFd) -> io::Result<bool> {
+    get_bool(fd, libc::IPPROTO_IPV6, IPV6_V6ONLY)
+}
+
+pub fn set_reuseaddr(fd: RawFd, reuse: bool) -> io::Result<()> {
+    set_bool(fd, libc::SOL_SOCKET, SO_REUSEADDR, reuse)
+}
+
+pub fn reuseaddr(fd: RawFd) -> io::Result<bool> {
+    get_bool(fd, libc::SOL_SOCKET, SO_REUSEADDR)
+}
+
+pub fn set_reuseport(fd: RawFd, reuse: bool) -> io::Result<()> {
+    set_bool(fd, libc::SOL_SOCKET, SO_REUSEPORT, reuse)
+}
+
+pub fn reuseport(fd: RawFd) -> io::Result<bool> {
+    get_bool(fd, libc::SOL_SOCKET, SO_REUSEPORT)
+}
+
+pub fn set_broadcast(fd: RawFd, broadcast: bool) -> io::Result<()> {
+    set_bool(fd, libc::SOL_SOCKET, SO_BROADCAST, broadcast)
+}
+
+pub fn broadcast(fd: RawFd) -> io::Result<bool> {
+    get_bool(fd, libc::SOL_SOCKET, SO_BROADCAST)
+}
+
+pub fn join_multicast_v4(fd: RawFd, multiaddr: &Ipv4Addr, interface: &Ipv4Addr) -> io::Result<()> {
+    let mreq = IpMreq {
+        imr_multiaddr: multiaddr.octets(),
+        imr_interface: interface.octets(),
+    };
+    setsockopt(fd, libc::IPPROTO_IP, IP_ADD_MEMBERSHIP, mreq)
+}
+
+pub fn leave_multicast_v4(fd: RawFd, multiaddr: &Ipv4Addr, interface: &Ipv4Addr) -> io::Result<()> {
+    let mreq = IpMreq {
+        imr_multiaddr: multiaddr.octets(),
+        imr_interface: interface.octets(),
+    };
+    setsockopt(fd, libc::IPPROTO_IP, IP_DROP_MEMBERSHIP, mreq)
+}
+
+pub fn join_multicast_v6(fd: RawFd, multiaddr: &Ipv6Addr, interface: u32) -> io::Result<()> {
+    let mreq = Ipv6Mreq {
+        ipv6mr_multiaddr: ipv6_octets(multiaddr),
+        ipv6mr_interface: interface as c_uint,
+    };
+    setsockopt(fd, libc::IPPROTO_IPV6, IPV6_JOIN_GROUP, mreq)
+}
+
+pub fn leave_multicast_v6(fd: RawFd, multiaddr: &Ipv6Addr, interface: u32) -> io::Result<()> {
+    let mreq = Ipv6Mreq {
+        ipv6mr_multiaddr: ipv6_octets(multiaddr),
+        ipv6mr_interface: interface as c_uint,
+    };
+    setsockopt(fd, libc::IPPROTO_IPV6, IPV6_LEAVE_GROUP, mreq)
+}
+
+pub fn set_multicast_if_v4(fd: RawFd, interface: &Ipv4Addr) -> io::Result<()> {
+    setsockopt(fd, libc::IPPROTO_IP, IP_MULTICAST_IF, interface.octets())
+}
+
+pub fn set_multicast_ttl_v4(fd: RawFd, ttl: u32) -> io::Result<()> {
+    setsockopt(fd, libc::IPPROTO_IP, IP_MULTICAST_TTL, ttl as McastV4Opt)
+}
+
+pub fn multicast_ttl_v4(fd: RawFd) -> io::Result<u32> {
+    let raw: McastV4Opt = try!(getsockopt(fd, libc::IPPROTO_IP, IP_MULTICAST_TTL));
+    Ok(raw as u32)
+}
+
+pub fn set_multicast_loop_v4(fd: RawFd, on: bool) -> io::Result<()> {
+    setsockopt(fd, libc::IPPROTO_IP, IP_MULTICAST_LOOP, on as McastV4Opt)
+}
+
+pub fn multicast_loop_v4(fd: RawFd) -> io::Result<bool> {
+    let raw: McastV4Opt = try!(getsockopt(fd, libc::IPPROTO_IP, IP_MULTICAST_LOOP));
+    Ok(raw != 0)
+}
+
+pub fn set_multicast_hops_v6(fd: RawFd, hops: u32) -> io::Result<()> {
+    set_u32(fd, libc::IPPROTO_IPV6, IPV6_MULTICAST_HOPS, hops)
+}
+
+pub fn multicast_hops_v6(fd: RawFd) -> io::Result<u32> {
+    get_u32(fd, libc::IPPROTO_IPV6, IPV6_MULTICAST_HOPS)
+}
+
+pub fn set_multicast_loop_v6(fd: RawFd, on: bool) -> io::Result<()> {
+    set_bool(fd, libc::IPPROTO_IPV6, IPV6_MULTICAST_LOOP, on)
+}
+
+pub fn multicast_loop_v6(fd: RawFd) -> io::Result<bool> {
+    get_bool(fd, libc::IPPROTO_IPV6, IPV6_MULTICAST_LOOP)
+}
@@ -0,0 +1,372 @@
+use std::io;
+use std::mem;
+use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6, Ipv4Addr, Ipv6Addr};
+
+use libc::{self, c_int, c_void, size_t, ssize_t, socklen_t};
+#[cfg(any(target_os = "linux",
+          target_os = "android"))]
+use libc::{c_uint, off_t};
+
+#[repr(C)]
+pub struct iovec {
+    pub iov_base: *mut c_void,
+    pub iov_len: size_t,
+}
+
+pub const MSG_PEEK: c_int = 0x2;
+
+#[cfg(any(target_os = "linux",
+          target_os = "android"))]
+pub const MSG_NOSIGNAL: c_int = 0x4000;
+#[cfg(any(target_os = "macos",
+          target_os = "freebsd",
+          target_os = "dragonfly",
+          target_os = "ios",
+          target_os = "bitrig",
+          target_os = "openbsd"))]
+pub const MSG_NOSIGNAL: c_int = 0;
+
+#[cfg(any(target_os = "linux",
+          target_os = "android"))]
+pub type sa_family_t = u16;
+
+#[cfg(any(target_os = "macos",
+          target_os = "freebsd",
+          target_os = "dragonfly",
+          target_os = "ios",
+          target_os = "bitrig",
+          target_os = "openbsd"))]
+pub type sa_family_t = u8;
+
+#[repr(C)]
+pub struct sockaddr_in {
+    #[cfg(any(target_os = "macos",
+              target_os = "freebsd",
+              target_os = "dragonfly",
+              target_os = "ios",
+              target_os = "bitrig",
+              target_os = "openbsd"))]
+    pub sin_len: u8,
+    pub sin_family: sa_family_t,
+    pub sin_port: u16,
+    pub sin_addr: [u8; 4],
+    pub sin_zero: [u8; 8],
+}
+
+#[repr(C)]
+pub struct sockaddr_in6 {
+    #[cfg(any(target_os = "macos",
+              target_os = "freebsd",
+              target_os = "dragonfly",
+              target_os = "ios",
+              target_os = "bitrig",
+              target_os = "openbsd"))]
+    pub sin6_len: u8,
+    pub sin6_family: sa_family_t,
+    pub sin6_port: u16,
+    pub sin6_flowinfo: u32,
+    pub sin6_addr: [u8; 16],
+    pub sin6_scope_id: u32,
+}
+
+/// Large and aligned enough for any socket address, like the C `sockaddr_storage`
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct sockaddr_storage {
+    data: [u64; 16],
+}
+
+impl sockaddr_storage {
+    pub fn new() -> sockaddr_storage {
+        sockaddr_storage { data: [0; 16] }
+    }
+
+    pub fn as_ptr(&self) -> *const libc::sockaddr {
+        self as *const sockaddr_storage as *const libc::sockaddr
+    }
+
+    pub fn as_mut_ptr(&mut self) -> *mut libc::sockaddr {
+        self as *mut sockaddr_storage as *mut libc::sockaddr
+    }
+
+    pub fn capacity() -> socklen_t {
+        mem::size_of::<sockaddr_storage>() as socklen_t
+    }
+}
+
+/// Converts a `SocketAddr` into its C representation
+pub fn sockaddr_from(addr: &SocketAddr) -> (sockaddr_storage, socklen_t) {
+    let mut storage = sockaddr_storage::new();
+
+    let len = match *addr {
+        SocketAddr::V4(ref a) => {
+            let sin = unsafe { &mut *(&mut storage as *mut sockaddr_storage as *mut sockaddr_in) };
+            init_len_v4(sin);
+            sin.sin_family = libc::AF_INET as sa_family_t;
+            sin.sin_port = a.port().to_be();
+            sin.sin_addr = a.ip().octets();
+            mem::size_of::<sockaddr_in>()
+        },
+        SocketAddr::V6(ref a) => {
+            let sin6 = unsafe { &mut *(&mut storage as *mut sockaddr_storage as *mut sockaddr_in6) };
+            init_len_v6(sin6);
+            sin6.sin6_family = libc::AF_INET6 as sa_family_t;
+            sin6.sin6_port = a.port().to_be();
+            sin6.sin6_flowinfo = a.flowinfo().to_be();
+            sin6.sin6_scope_id = a.scope_id();
+            for (idx, seg) in a.ip().segments().iter().enumerate() {
+                sin6.sin6_addr[idx * 2] = (*seg >> 8) as u8;
+                sin6.sin6_addr[idx * 2 + 1] = *seg as u8;
+            }
+            mem::size_of::<sockaddr_in6>()
+        }
+    };
+
+    (storage, len as socklen_t)
+}
+
+#[cfg(any(target_os = "linux",
+          target_os = "android"))]
+fn init_len_v4(_: &mut sockaddr_in) {}
+
+#[cfg(any(target_os = "linux",
+          target_os = "android"))]
+fn init_len_v6(_: &mut sockaddr_in6) {}
+
+#[cfg(any(target_os = "macos",
+          target_os = "freebsd",
+          target_os = "dragonfly",
+          target_os = "ios",
+          target_os = "bitrig",
+          target_os = "openbsd"))]
+fn init_len_v4(sin: &mut sockaddr_in) {
+    sin.sin_len = mem::size_of::<sockaddr_in>() as u8;
+}
+
+#[cfg(any(target_os = "macos",
+          target_os = "freebsd",
+          target_os = "dragonfly",
+          target_os = "ios",
+          target_os = "bitrig",
+          target_os = "openbsd"))]
+fn init_len_v6(sin6: &mut sockaddr_in6) {
+    sin6.sin6_len = mem::size_of::<sockaddr_in6>() as u8;
+}
+
+/// Converts the C representation of an IPv4 or IPv6 address into a `SocketAddr`
+pub fn sockaddr_to(storage: &sockaddr_storage, len: socklen_t) -> io::Result<SocketAddr> {
+    let family = unsafe { (*(storage as *const sockaddr_storage as *const sockaddr_in)).sin_family };
+
+    match family as c_int {
+        libc::AF_INET if len as usize >= mem::size_of::<sockaddr_in>() => {
+            let sin = unsafe { &*(storage as *const sockaddr_storage as *const sockaddr_in) };
+            let a = sin.sin_addr;
+            let ip = Ipv4Addr::new(a[0], a[1], a[2], a[3]);
+            Ok(SocketAddr::V4(SocketAddrV4::new(ip, u16::from_be(sin.sin_port))))
+        },
+        libc::AF_INET6 if len as usize >= mem::size_of::<sockaddr_in6>() => {
+            let sin6 = unsafe { &*(storage as *const sockaddr_storage as *const sockaddr_in6) };
+            let mut segs = [0u16; 8];
+            for idx in 0..8 {
+                segs[idx] = (sin6.sin6_addr[idx * 2] as u16) << 8 | sin6.sin6_addr[idx * 2 + 1] as u16;
+            }
+            let ip = Ipv6Addr::new(segs[0], segs[1], segs[2], segs[3],
+                                   segs[4], segs[5], segs[6], segs[7]);
+            Ok(SocketAddr::V6(SocketAddrV6::new(ip, u16::from_be(sin6.sin6_port),
+                                                u32::from_be(sin6.sin6_flowinfo),
+                                                sin6.sin6_scope_id)))
+        },
+        _ => Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid socket address family"))
+    }
+}
+
+#[cfg(any(target_os = "linux",
+          target_os = "android"))]
+pub const SPLICE_F_MOVE: c_uint = 1;
+#[cfg(any(target_os = "linux",
+          target_os = "android"))]
+pub const SPLICE_F_NONBLOCK: c_uint = 2;
+#[cfg(any(target_os = "linux",
+          target_os = "android"))]
+pub const O_CLOEXEC: c_int = 0o2000000;
+
+extern {
+    pub fn readv(fd: c_int, iov: *const iovec, iovcnt: c_int) -> ssize_t;
+    pub fn writev(fd: c_int, iov: *const iovec, iovcnt: c_int) -> ssize_t;
+}
+
+#[cfg(any(target_os = "linux",
+          target_os = "android"))]
+#[repr(C)]
+pub struct msghdr {
+    pub msg_name: *mut c_void,
+    pub msg_namelen: socklen_t,
+    pub msg_iov: *mut iovec,
+    pub msg_iovlen: size_t,
+    pub msg_control: *mut c_void,
+    pub msg_controllen: size_t,
+    pub msg_flags: c_int,
+}
+
+#[cfg(any(target_os = "macos",
+          target_os = "freebsd",
+          target_os = "dragonfly",
+          target_os = "ios",
+          target_os = "bitrig",
+          target_os = "openbsd"))]
+#[repr(C)]
+pub struct msghdr {
+    pub msg_name: *mut c_void,
+    pub msg_namelen: socklen_t,
+    pub msg_iov: *mut iovec,
+    pub msg_iovlen: c_int,
+    pub msg_control: *mut c_void,
+    pub msg_controllen: socklen_t,
+    pub msg_flags: c_int,
+}
+
+#[cfg(any(target_os = "linux",
+          target_os = "android"))]
+#[repr(C)]
+pub struct cmsghdr {
+    pub cmsg_len: size_t,
+    pub cmsg_level: c_int,
+    pub cmsg_type: c_int,
+}
+
+#[cfg(any(target_os = "macos",
+          target_os = "freebsd",
+          target_os = "dragonfly",
+          target_os = "ios",
+          target_os = "bitrig",
+          target_os = "openbsd"))]
+#[repr(C)]
+pub struct cmsghdr {
+    pub cmsg_len: socklen_t,
+    pub cmsg_level: c_int,
+    pub cmsg_type: c_int,
+}
+
+pub const SCM_RIGHTS: c_int = 1;
+
+#[cfg(any(target_os = "linux",
+          target_os = "android"))]
+pub const MSG_CTRUNC: c_int = 0x8;
+#[cfg(any(target_os = "macos",
+          target_os = "freebsd",
+          target_os = "dragonfly",
+          target_os = "ios",
+          target_os = "bitrig",
+          target_os = "openbsd"))]
+pub const MSG_CTRUNC: c_int = 0x20;
+
+#[cfg(any(target_os = "linux",
+          target_os = "android"))]
+pub const MSG_CMSG_CLOEXEC: c_int = 0x40000000;
+#[cfg(any(target_os = "macos",
+          target_os = "freebsd",
+          target_os = "dragonfly",
+          target_os = "ios",
+          target_os = "bitrig",
+          target_os = "openbsd"))]
+pub const MSG_CMSG_CLOEXEC: c_int = 0;
+
+#[cfg(any(target_os = "linux",
+          target_os = "android"))]
+const CMSG_ALIGN_TO: usize = 8; // sizeof(size_t)
+#[cfg(any(target_os = "macos",
+          target_os = "ios"))]
+const CMSG_ALIGN_TO: usize = 4; // sizeof(u_int32_t)
+#[cfg(any(target_os = "freebsd",
+          target_os = "dragonfly",
+          target_os = "bitrig",
+          target_os = "openbsd"))]
+const CMSG_ALIGN_TO: usize = 8; // sizeof(long)
+
+fn cmsg_align(len: usize) -> usize {
+    (len + CMSG_ALIGN_TO - 1) & !(CMSG_ALIGN_TO - 1)
+}
+
+/// `CMSG_SPACE`: bytes taken in the control buffer by a control message with `len` bytes of data
+pub fn cmsg_space(len: usize) -> usize {
+    cmsg_align(mem::size_of::<cmsghdr>()) + cmsg_align(len)
+}
+
+/// `CMSG_LEN`: value of `cmsg_len` for a control message with `len` bytes of data
+pub fn cmsg_len(len: usize) -> usize {
+    cmsg_align(mem::size_of::<cmsghdr>()) + len
+}
+
+/// `CMSG_DATA`: data of the control message starting at `cmsg`
+pub unsafe fn cmsg_data(cmsg: *mut cmsghdr) -> *mut u8 {
+    (cmsg as *mut u8).offset(cmsg_align(mem::size_of::<cmsghdr>()) as isize)
+}
+
+extern {
+    pub fn sendmsg(fd: c_int, msg: *const msghdr, flags: c_int) -> ssize_t;
+    pub fn recvmsg(fd: c_int, msg: *mut msghdr, flags: c_int) -> ssize_t;
+    pub fn socketpair(domain: c_int, ty: c_int, protocol: c_int, sv: *mut c_int) -> c_int;
+}
+
+#[cfg(any(target_os = "linux",
+          target_os = "android"))]
+#[repr(C)]
+pub struct sockaddr_un {
+    pub sun_family: sa_family_t,
+    pub sun_path: [u8; 108],
+}
+
+#[cfg(any(target_os = "macos",
+          target_os = "freebsd",
+          target_os = "dragonfly",
+          target_os = "ios",
+          target_os = "bitrig",
+          target_os = "openbsd"))]
+#[repr(C)]
+pub struct sockaddr_un {
+    pub sun_len: u8,
+    pub sun_family: sa_family_t,
+    pub sun_path: [u8; 104],
+}
+
+#[cfg(any(target_os = "linux",
+          target_os = "android"))]
+#[repr(C)]
+pub struct mmsghdr {
+    pub msg_hdr: msghdr,
+    pub msg_len: c_uint,
+}
+
+#[cfg(any(target_os = "linux",
+          target_os = "android"))]
+extern {
+    pub fn recvmmsg(fd: c_int, msgvec: *mut mmsghdr, vlen: c_uint, flags: c_int,
+                    timeout: *mut c_void) -> c_int;
+    pub fn sendmmsg(fd: c_int, msgvec: *mut mmsghdr, vlen: c_uint, flags: c_int) -> c_int;
+    pub fn sendfile(out_fd: c_int, in_fd: c_int, offset: *mut off_t, count: size_t) -> ssize_t;
+    pub fn splice(fd_in: c_int, off_in: *mut i64, fd_out: c_int, off_out: *mut i64,
+                  len: size_t, flags: c_uint) -> ssize_t;
+    pub fn pipe2(fds: *mut c_int, flags: c_int) -> c_int;
+}
+
+/// Converts the return value of a system call into an `io::Result`
+pub fn cvt(ret: ssize_t) -> io::Result<usize> {
+    if ret == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret as usize)
+    }
+}
+
+/// Puts `fd` into non-blocking mode and marks it close-on-exec
+pub fn set_nonblocking_cloexec(fd: c_int) -> io::Result<()> {
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFL);
+        if flags == -1
+                || libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) == -1
+                || libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
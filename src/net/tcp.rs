@@ -1,11 +1,71 @@
 use std::io;
-use std::net::{ToSocketAddrs, SocketAddr};
+use std::mem;
+use std::net::{ToSocketAddrs, SocketAddr, Shutdown};
 use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex};
+use std::os::unix::io::{AsRawFd, RawFd};
+#[cfg(any(target_os = "linux",
+          target_os = "android"))]
+use std::fs::File;
+#[cfg(any(target_os = "linux",
+          target_os = "android"))]
+use std::ptr;
+
+use libc::{self, c_int, c_void, size_t};
+#[cfg(any(target_os = "linux",
+          target_os = "android"))]
+use libc::off_t;
 
 use mio::{self, Interest};
 use mio::buf::{Buf, MutBuf, MutSliceBuf, SliceBuf};
 
 use scheduler::Scheduler;
+use sync::Notify;
+use super::sockopt;
+use super::sys;
+use scheduler::retry_nonblocking;
+
+/// Progress of the accept loops of `TcpListener::bind_sharded`
+#[cfg(any(target_os = "linux",
+          target_os = "android"))]
+struct ShardState {
+    /// Error of the first accept loop which failed
+    failure: Option<io::Error>,
+    /// Accept loops which have not returned yet
+    running: usize,
+}
+
+/// Binds a `SO_REUSEPORT` listener for `TcpListener::bind_sharded`
+#[cfg(any(target_os = "linux",
+          target_os = "android"))]
+fn bind_reuseport(addr: &SocketAddr) -> io::Result<TcpListener> {
+    let sock = try!(match *addr {
+        SocketAddr::V4(..) => TcpSocket::v4(),
+        SocketAddr::V6(..) => TcpSocket::v6(),
+    });
+    try!(sock.set_reuseaddr(true));
+    try!(sock.set_reuseport(true));
+    try!(sock.bind(addr));
+    sock.listen(SHARDED_BACKLOG)
+}
+
+/// Backlog of every listener opened by `TcpListener::bind_sharded`
+#[cfg(any(target_os = "linux",
+          target_os = "android"))]
+const SHARDED_BACKLOG: usize = 1024;
+
+/// Maximum connections accepted per wakeup by the accept loops of `TcpListener::bind_sharded`
+#[cfg(any(target_os = "linux",
+          target_os = "android"))]
+const SHARDED_ACCEPT_BATCH: usize = 64;
+
+/// Longest pause of an accept loop of `TcpListener::bind_sharded` after a transient error
+#[cfg(any(target_os = "linux",
+          target_os = "android"))]
+const MAX_ACCEPT_ERROR_DELAY_MS: u64 = 1000;
+
+/// Delay between two connection attempts of `connect_happy_eyeballs`, as recommended by RFC 8305
+const CONNECTION_ATTEMPT_DELAY_MS: u64 = 250;
 
 pub struct TcpSocket(::mio::tcp::TcpSocket);
 
@@ -32,11 +92,23 @@ impl TcpSocket {
         }).map(|(stream, complete)| (TcpStream(stream), complete))
     }
 
+    pub fn bind(&self, addr: &SocketAddr) -> io::Result<()> {
+        self.0.bind(addr)
+    }
+
     pub fn listen(self, backlog: usize) -> io::Result<TcpListener> {
         Ok(TcpListener(try!(self.0.listen(backlog))))
     }
 }
 
+impl AsRawFd for TcpSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
+impl_tcp_opts!(TcpSocket);
+
 impl Deref for TcpSocket {
     type Target = ::mio::tcp::TcpSocket;
 
@@ -61,6 +133,120 @@ impl TcpListener {
         super::each_addr(addr, ::mio::tcp::TcpListener::bind).map(TcpListener)
     }
 
+    /// Binds one `SO_REUSEPORT` listener per scheduler thread, and runs an accept loop for each of
+    /// them in a coroutine pinned to its thread
+    ///
+    /// The kernel balances the incoming connections between the listeners, and `handler` is called
+    /// in a new coroutine for every accepted stream. All listeners share the address of the first
+    /// one, so port 0 picks a single ephemeral port. Transient errors like running out of
+    /// descriptors are logged, and the accept loop pauses before it goes on. Blocks the current
+    /// coroutine until one of the accept loops fails otherwise, stops the others, closes every
+    /// listener and returns that error.
+    ///
+    /// Only Linux balances connections between `SO_REUSEPORT` listeners, and wakes up a listener
+    /// which is shut down. Elsewhere this fails at once.
+    #[cfg(any(target_os = "linux",
+              target_os = "android"))]
+    pub fn bind_sharded<A, F>(addr: A, handler: F) -> io::Result<()>
+            where A: ToSocketAddrs,
+                  F: Fn(TcpStream) + Send + Sync + 'static {
+
+        let threads = Scheduler::threads();
+        let listeners = try!(super::each_addr(addr, |a| {
+            let mut listeners = Vec::with_capacity(threads);
+            let mut bound = *a;
+            for _ in 0..threads {
+                let listener = try!(bind_reuseport(&bound));
+                if listeners.is_empty() {
+                    bound = try!(listener.local_addr());
+                }
+                listeners.push(Arc::new(listener));
+            }
+            Ok(listeners)
+        }));
+
+        let handler = Arc::new(handler);
+        let shards = Arc::new(Mutex::new(ShardState {
+            failure: None,
+            running: listeners.len(),
+        }));
+        let failed = Notify::new();
+        let stopped = Notify::new();
+
+        for (idx, listener) in listeners.iter().cloned().enumerate() {
+            let handler = handler.clone();
+            let shards = shards.clone();
+            let failed = failed.clone();
+            let stopped = stopped.clone();
+
+            Scheduler::spawn_pinned(idx, move|| {
+                debug!("Shard {} accepting on {:?}", idx, listener.local_addr());
+
+                let mut delay = 0;
+                loop {
+                    match listener.accept_batch(SHARDED_ACCEPT_BATCH) {
+                        Ok(streams) => {
+                            delay = 0;
+                            for stream in streams.into_iter() {
+                                let handler = handler.clone();
+                                Scheduler::spawn(move|| handler(stream));
+                            }
+                        },
+                        Err(ref err) if is_transient_accept_error(err) => {
+                            delay = ::std::cmp::max(delay * 2, 10);
+                            delay = ::std::cmp::min(delay, MAX_ACCEPT_ERROR_DELAY_MS);
+                            warn!("Shard {} accept error: {:?}; retrying in {}ms", idx, err, delay);
+                            if let Err(err) = Scheduler::current().sleep_ms(delay) {
+                                debug!("Shard {} cannot pause: {:?}", idx, err);
+                            }
+                        },
+                        Err(err) => {
+                            let mut shards = shards.lock().unwrap();
+                            if shards.failure.is_none() {
+                                error!("Shard {} accept error: {:?}", idx, err);
+                                shards.failure = Some(err);
+                                failed.notify();
+                            } else {
+                                debug!("Shard {} stopped", idx);
+                            }
+
+                            shards.running -= 1;
+                            if shards.running == 0 {
+                                stopped.notify();
+                            }
+                            return;
+                        }
+                    }
+                }
+            });
+        }
+
+        failed.wait();
+
+        // Wakes up the other accept loops, which fail and see that a shard already failed. The
+        // listeners are still open here, so their descriptors cannot have been reused.
+        for listener in listeners.iter() {
+            let _ = unsafe { libc::shutdown(listener.as_raw_fd(), libc::SHUT_RD) };
+        }
+        stopped.wait();
+        drop(listeners);
+
+        let err = shards.lock().unwrap().failure.take().unwrap();
+        Err(err)
+    }
+
+    #[cfg(any(target_os = "macos",
+              target_os = "freebsd",
+              target_os = "dragonfly",
+              target_os = "ios",
+              target_os = "bitrig",
+              target_os = "openbsd"))]
+    pub fn bind_sharded<A, F>(_addr: A, _handler: F) -> io::Result<()>
+            where A: ToSocketAddrs,
+                  F: Fn(TcpStream) + Send + Sync + 'static {
+        Err(io::Error::new(io::ErrorKind::Other, "sharded listeners are only supported on Linux"))
+    }
+
     pub fn accept(&self) -> io::Result<TcpStream> {
         match self.0.accept() {
             Ok(None) => {
@@ -89,11 +275,56 @@ impl TcpListener {
         }
     }
 
+    /// Accepts up to `max` connections at once
+    ///
+    /// Drains the backlog until it would block, and only parks the current coroutine if no
+    /// connection is pending. The returned `Vec` is never empty.
+    pub fn accept_batch(&self, max: usize) -> io::Result<Vec<TcpStream>> {
+        assert!(max >= 1, "max must >= 1");
+
+        let mut streams = Vec::new();
+        loop {
+            while streams.len() < max {
+                match self.0.accept() {
+                    Ok(None) => {
+                        debug!("accept_batch WouldBlock after {} streams", streams.len());
+                        break;
+                    },
+                    Ok(Some(stream)) => {
+                        streams.push(TcpStream(stream));
+                    },
+                    Err(err) => {
+                        if streams.is_empty() {
+                            return Err(err);
+                        }
+                        // Keep the accepted streams, a persistent error shows up again on the next call
+                        warn!("accept_batch error after {} streams: {:?}", streams.len(), err);
+                        break;
+                    }
+                }
+            }
+
+            if !streams.is_empty() {
+                return Ok(streams);
+            }
+
+            try!(Scheduler::current().wait_event(&self.0, Interest::readable()));
+        }
+    }
+
     pub fn try_clone(&self) -> io::Result<TcpListener> {
         Ok(TcpListener(try!(self.0.try_clone())))
     }
 }
 
+impl AsRawFd for TcpListener {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
+impl_tcp_opts!(TcpListener);
+
 impl Deref for TcpListener {
     type Target = ::mio::tcp::TcpListener;
 
@@ -118,6 +349,101 @@ impl TcpStream {
         super::each_addr(addr, ::mio::tcp::TcpStream::connect).map(TcpStream)
     }
 
+    /// Connects to all the resolved addresses of `addr` concurrently (Happy Eyeballs, RFC 8305)
+    ///
+    /// Addresses are interleaved by family, IPv6 first, and every attempt runs in its own coroutine.
+    /// The next attempt starts as soon as the previous one fails, or `CONNECTION_ATTEMPT_DELAY_MS`
+    /// after it started. The first connection to succeed is returned. The attempts still connecting
+    /// at that point are aborted by shutting down their sockets, and the ones which connected too
+    /// are closed. If every attempt fails, the error of the last one is returned.
+    pub fn connect_happy_eyeballs<A: ToSocketAddrs>(addr: A) -> io::Result<TcpStream> {
+        let addrs = interleave_families(try!(addr.to_socket_addrs()).collect());
+        if addrs.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "could not resolve to any addresses"));
+        }
+
+        let race = Arc::new(Mutex::new(ConnectRace {
+            winner: None,
+            pending: addrs.len(),
+            last_err: None,
+            connecting: Vec::new(),
+        }));
+        let done = Notify::new();
+        let attempts = addrs.len();
+
+        for (idx, addr) in addrs.into_iter().enumerate() {
+            if race.lock().unwrap().winner.is_some() {
+                debug!("Happy eyeballs: {} skipped, already connected", addr);
+                break;
+            }
+
+            // Notified when this attempt completes or the attempt delay has passed
+            let next = Notify::new();
+
+            let race = race.clone();
+            let done = done.clone();
+            let attempt_done = next.clone();
+            Scheduler::spawn(move|| {
+                let result = connect_raced(idx, &addr, &race);
+
+                let finished = {
+                    let mut race = race.lock().unwrap();
+                    race.pending -= 1;
+                    race.connecting.retain(|&(attempt, _)| attempt != idx);
+
+                    match result {
+                        Ok(stream) => {
+                            if race.winner.is_none() {
+                                debug!("Happy eyeballs: {} won", addr);
+                                race.winner = Some(stream);
+                                // Wakes up the other attempts, which are dropped when they see the winner
+                                for (_, other) in mem::replace(&mut race.connecting, Vec::new()) {
+                                    let _ = other.shutdown(Shutdown::Both);
+                                }
+                                true
+                            } else {
+                                // Dropping the stream closes the loser
+                                false
+                            }
+                        },
+                        Err(err) => {
+                            debug!("Happy eyeballs: {} failed: {:?}", addr, err);
+                            race.last_err = Some(err);
+                            race.pending == 0 && race.winner.is_none()
+                        },
+                    }
+                };
+
+                if finished {
+                    done.notify();
+                }
+                attempt_done.notify();
+            });
+
+            if idx + 1 < attempts {
+                let delay_done = next.clone();
+                Scheduler::spawn(move|| {
+                    if let Err(err) = Scheduler::current().sleep_ms(CONNECTION_ATTEMPT_DELAY_MS) {
+                        debug!("Happy eyeballs: cannot wait for the attempt delay: {:?}", err);
+                    }
+                    delay_done.notify();
+                });
+                next.wait();
+            }
+        }
+
+        done.wait();
+
+        let mut race = race.lock().unwrap();
+        match race.winner.take() {
+            Some(stream) => Ok(stream),
+            None => Err(race.last_err.take().unwrap_or_else(|| {
+                io::Error::new(io::ErrorKind::Other, "all connection attempts failed")
+            })),
+        }
+    }
+
     pub fn peer_addr(&self) -> io::Result<SocketAddr> {
         self.0.peer_addr()
     }
@@ -131,6 +457,228 @@ impl TcpStream {
 
         Ok(TcpStream(stream))
     }
+
+    /// Reads like `read`, but fails with `TimedOut` if no data arrives within `timeout_ms`
+    pub fn read_timeout(&mut self, buf: &mut [u8], timeout_ms: u64) -> io::Result<usize> {
+        use mio::TryRead;
+
+        loop {
+            match self.0.read(&mut MutSliceBuf::wrap(buf)) {
+                Ok(None) => {
+                    debug!("TcpStream read WouldBlock, waiting at most {}ms", timeout_ms);
+                    try!(Scheduler::current().wait_event_timeout(&self.0, Interest::readable(), timeout_ms));
+                },
+                Ok(Some(len)) => return Ok(len),
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Shuts down the read, write, or both halves of this connection
+    ///
+    /// Shutting down a connection which is already closed by the peer is not an error.
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        let how = match how {
+            Shutdown::Read => libc::SHUT_RD,
+            Shutdown::Write => libc::SHUT_WR,
+            Shutdown::Both => libc::SHUT_RDWR,
+        };
+
+        match unsafe { libc::shutdown(self.as_raw_fd(), how) } {
+            -1 => {
+                let err = io::Error::last_os_error();
+                match err.kind() {
+                    // see https://github.com/hyperium/hyper/issues/508
+                    io::ErrorKind::NotConnected => Ok(()),
+                    _ => Err(err),
+                }
+            },
+            _ => Ok(()),
+        }
+    }
+
+    /// Reads into several buffers with one `readv`, blocking the coroutine until some data arrives
+    pub fn read_vectored(&mut self, bufs: &mut [&mut [u8]]) -> io::Result<usize> {
+        let iovecs = bufs.iter_mut().map(|buf| {
+            sys::iovec {
+                iov_base: buf.as_mut_ptr() as *mut c_void,
+                iov_len: buf.len() as size_t,
+            }
+        }).collect::<Vec<sys::iovec>>();
+
+        let fd = self.as_raw_fd();
+        retry_nonblocking(&self.0, Interest::readable(), || unsafe {
+            sys::cvt(sys::readv(fd, iovecs.as_ptr(), iovecs.len() as c_int))
+        })
+    }
+
+    /// Writes several buffers with one `writev`, blocking the coroutine until some data is written
+    pub fn write_vectored(&mut self, bufs: &[&[u8]]) -> io::Result<usize> {
+        let iovecs = bufs.iter().map(|buf| {
+            sys::iovec {
+                iov_base: buf.as_ptr() as *mut c_void,
+                iov_len: buf.len() as size_t,
+            }
+        }).collect::<Vec<sys::iovec>>();
+
+        let fd = self.as_raw_fd();
+        retry_nonblocking(&self.0, Interest::writable(), || unsafe {
+            sys::cvt(sys::writev(fd, iovecs.as_ptr(), iovecs.len() as c_int))
+        })
+    }
+
+    /// Sends up to `count` bytes of `file` from `offset` with `sendfile`, without copying them
+    /// through userspace
+    ///
+    /// Blocks the coroutine until the socket is writable. Returns the number of bytes sent, which
+    /// is 0 at the end of the file.
+    #[cfg(any(target_os = "linux",
+              target_os = "android"))]
+    pub fn sendfile(&mut self, file: &File, offset: u64, count: usize) -> io::Result<usize> {
+        let fd = self.as_raw_fd();
+        let file_fd = file.as_raw_fd();
+        retry_nonblocking(&self.0, Interest::writable(), || {
+            let mut off = offset as off_t;
+            unsafe {
+                sys::cvt(sys::sendfile(fd, file_fd, &mut off, count as size_t))
+            }
+        })
+    }
+
+    /// Moves up to `count` bytes from this stream to `to` with `splice`, without copying them
+    /// through userspace
+    ///
+    /// The bytes pass through `pipe`, which can be reused for every call on the same pair of
+    /// streams. Blocks the coroutine until some bytes are read, then until all of them are written
+    /// to `to`. Returns the number of bytes moved, which is 0 on EOF.
+    #[cfg(any(target_os = "linux",
+              target_os = "android"))]
+    pub fn splice_to(&mut self, to: &mut TcpStream, pipe: &SplicePipe, count: usize)
+            -> io::Result<usize> {
+        let flags = sys::SPLICE_F_MOVE | sys::SPLICE_F_NONBLOCK;
+
+        let fd = self.as_raw_fd();
+        let len = try!(retry_nonblocking(&self.0, Interest::readable(), || unsafe {
+            sys::cvt(sys::splice(fd, ptr::null_mut(), pipe.wr, ptr::null_mut(), count as size_t, flags))
+        }));
+
+        let to_fd = to.as_raw_fd();
+        let mut written = 0;
+        while written < len {
+            let remain = len - written;
+            let n = try!(retry_nonblocking(&to.0, Interest::writable(), || unsafe {
+                sys::cvt(sys::splice(pipe.rd, ptr::null_mut(), to_fd, ptr::null_mut(), remain as size_t, flags))
+            }));
+            written += n;
+        }
+
+        Ok(len)
+    }
+}
+
+/// Kernel buffer for `TcpStream::splice_to`
+///
+/// If a call fails, the pipe may still hold bytes which were read but not written, so it should
+/// not be used for other streams afterwards.
+#[cfg(any(target_os = "linux",
+          target_os = "android"))]
+pub struct SplicePipe {
+    rd: c_int,
+    wr: c_int,
+}
+
+#[cfg(any(target_os = "linux",
+          target_os = "android"))]
+impl SplicePipe {
+    pub fn new() -> io::Result<SplicePipe> {
+        let mut fds = [0; 2];
+        if unsafe { sys::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | sys::O_CLOEXEC) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(SplicePipe {
+            rd: fds[0],
+            wr: fds[1],
+        })
+    }
+}
+
+#[cfg(any(target_os = "linux",
+          target_os = "android"))]
+impl Drop for SplicePipe {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.rd);
+            libc::close(self.wr);
+        }
+    }
+}
+
+struct ConnectRace {
+    winner: Option<TcpStream>,
+    pending: usize,
+    last_err: Option<io::Error>,
+    /// Other handles to the sockets of the attempts which are still connecting, by attempt
+    connecting: Vec<(usize, TcpStream)>,
+}
+
+/// Connects the `idx`-th attempt of a race, registering its socket while it is connecting
+fn connect_raced(idx: usize, addr: &SocketAddr, race: &Mutex<ConnectRace>) -> io::Result<TcpStream> {
+    let (stream, complete) = try!(TcpSocket::connect(addr));
+
+    if !complete {
+        {
+            let mut race = race.lock().unwrap();
+            if race.winner.is_some() {
+                return Err(io::Error::new(io::ErrorKind::Other, "another attempt already connected"));
+            }
+            race.connecting.push((idx, try!(stream.try_clone())));
+        }
+
+        debug!("Connect: Going to register event");
+        try!(Scheduler::current().wait_event(&stream.0, Interest::writable()));
+        debug!("Connect: Got write event");
+
+        if let Some(err) = try!(sockopt::take_error(&stream.0)) {
+            return Err(err);
+        }
+    }
+
+    Ok(stream)
+}
+
+/// Whether an accept loop should pause and go on after `err`, instead of stopping
+#[cfg(any(target_os = "linux",
+          target_os = "android"))]
+fn is_transient_accept_error(err: &io::Error) -> bool {
+    match err.raw_os_error() {
+        Some(libc::EMFILE) | Some(libc::ENFILE) | Some(libc::ENOBUFS) | Some(libc::ENOMEM) |
+        Some(libc::ECONNABORTED) | Some(libc::EINTR) => true,
+        _ => false,
+    }
+}
+
+fn interleave_families(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let (v6, v4): (Vec<SocketAddr>, Vec<SocketAddr>) = addrs.into_iter().partition(|a| {
+        match a {
+            &SocketAddr::V6(..) => true,
+            &SocketAddr::V4(..) => false,
+        }
+    });
+
+    let mut result = Vec::with_capacity(v6.len() + v4.len());
+    let mut v6 = v6.into_iter();
+    let mut v4 = v4.into_iter();
+    loop {
+        match (v6.next(), v4.next()) {
+            (None, None) => break,
+            (a, b) => {
+                result.extend(a);
+                result.extend(b);
+            }
+        }
+    }
+    result
 }
 
 impl io::Read for TcpStream {
@@ -256,6 +804,14 @@ impl io::Write for TcpStream {
     }
 }
 
+impl AsRawFd for TcpStream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
+impl_tcp_opts!(TcpStream);
+
 impl Deref for TcpStream {
     type Target = ::mio::tcp::TcpStream;
 
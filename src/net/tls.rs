@@ -0,0 +1,573 @@
+//! TLS streams on top of the coroutine sockets
+//!
+//! `SslStream` drives OpenSSL through memory BIOs, so whenever OpenSSL wants to read or write
+//! (`SSL_ERROR_WANT_READ` / `SSL_ERROR_WANT_WRITE`) it does so on the wrapped stream. For the
+//! coroutine sockets of this crate that parks the current coroutine until the socket is ready,
+//! therefore handshakes and reads never block the scheduler thread.
+
+use std::ascii::AsciiExt;
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, Shutdown, Ipv4Addr, Ipv6Addr};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard, RwLock, TryLockError};
+
+use coroutine::coroutine::Coroutine;
+use mio::Interest;
+
+use openssl::ssl::{Ssl, SslContext, SslStream, SSL_VERIFY_NONE, SSL_VERIFY_PEER, SSL_VERIFY_FAIL_IF_NO_PEER_CERT};
+use openssl::ssl::SslMethod::Sslv23;
+use openssl::ssl::error::SslError;
+use openssl::ssl::error::StreamError as SslIoError;
+use openssl::nid::Nid;
+use openssl::x509::{X509, X509FileType, X509Name};
+
+use libc::{c_char, c_void};
+
+use scheduler::Scheduler;
+
+use super::tcp::TcpStream;
+use super::Duplex;
+
+/// Returned by the SNI callback to continue the handshake
+const SSL_TLSEXT_ERR_OK: i32 = 0;
+
+type SniMap = HashMap<String, Arc<SslContext>>;
+
+// Not bound by rust-openssl
+extern {
+    fn SSL_load_client_CA_file(file: *const c_char) -> *mut c_void;
+    fn SSL_CTX_set_client_CA_list(ctx: *mut c_void, names: *mut c_void);
+}
+
+/// Accepts TLS connections with the certificate of an `SslContext`
+#[derive(Clone)]
+pub struct TlsAcceptor {
+    ctx: Arc<SslContext>,
+    hosts: Arc<SniMap>,
+}
+
+impl TlsAcceptor {
+    pub fn new(ctx: SslContext) -> TlsAcceptor {
+        TlsAcceptor::with_sni(ctx, HashMap::new())
+    }
+
+    /// Creates an acceptor which picks the context by the SNI hostname of the client
+    ///
+    /// Hostnames are matched case-insensitively. Clients without SNI, or asking for an unknown
+    /// hostname, get `default`.
+    pub fn with_sni(default: SslContext, hosts: HashMap<String, SslContext>) -> TlsAcceptor {
+        let hosts: SniMap = hosts.into_iter()
+                                 .map(|(host, ctx)| (host.to_ascii_lowercase(), Arc::new(ctx)))
+                                 .collect();
+        let hosts = Arc::new(hosts);
+
+        if !hosts.is_empty() {
+            default.set_servername_callback_with_data(select_context, hosts.clone());
+        }
+
+        TlsAcceptor {
+            ctx: Arc::new(default),
+            hosts: hosts,
+        }
+    }
+
+    /// Creates an acceptor serving the certificate and private key in the given PEM files
+    pub fn from_pem_files(cert: &Path, key: &Path) -> io::Result<TlsAcceptor> {
+        Ok(TlsAcceptor::new(try!(server_context(cert, key))))
+    }
+
+    pub fn context(&self) -> &SslContext {
+        &self.ctx
+    }
+
+    /// Context used for clients asking for `hostname` with SNI
+    pub fn context_for(&self, hostname: &str) -> &SslContext {
+        match self.hosts.get(&hostname.to_ascii_lowercase()) {
+            Some(ctx) => ctx,
+            None => &self.ctx,
+        }
+    }
+
+    /// Performs the server side handshake on `stream`, parking the current coroutine while it waits for the peer
+    pub fn accept<S: Read + Write>(&self, stream: S) -> io::Result<TlsStream<S>> {
+        let ssl = try!(Ssl::new(&self.ctx).map_err(from_ssl_error));
+        let stream = try!(SslStream::new_server_from(ssl, stream).map_err(from_ssl_error));
+        Ok(TlsStream { inner: stream })
+    }
+}
+
+/// A `TlsAcceptor` which can be replaced while connections are being accepted
+///
+/// Every connection takes a snapshot of the current acceptor with `current`, so handshakes in
+/// flight and established connections keep the old certificates after a `reload`.
+#[derive(Clone)]
+pub struct ReloadableAcceptor {
+    current: Arc<RwLock<TlsAcceptor>>,
+}
+
+impl ReloadableAcceptor {
+    pub fn new(acceptor: TlsAcceptor) -> ReloadableAcceptor {
+        ReloadableAcceptor {
+            current: Arc::new(RwLock::new(acceptor)),
+        }
+    }
+
+    /// The acceptor for new connections
+    pub fn current(&self) -> TlsAcceptor {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Swaps in `acceptor` for all connections accepted from now on
+    pub fn reload(&self, acceptor: TlsAcceptor) {
+        *self.current.write().unwrap() = acceptor;
+        debug!("TLS acceptor reloaded");
+    }
+
+    /// Performs the server side handshake with the current acceptor
+    pub fn accept<S: Read + Write>(&self, stream: S) -> io::Result<TlsStream<S>> {
+        self.current().accept(stream)
+    }
+}
+
+/// Opens TLS connections, optionally verifying the certificate of the server
+#[derive(Clone)]
+pub struct TlsConnector {
+    ctx: Arc<SslContext>,
+    verify_hostname: bool,
+}
+
+impl TlsConnector {
+    /// Creates a connector which trusts the CAs in the given PEM bundle and checks the server hostname
+    pub fn verifying(ca_file: &Path) -> io::Result<TlsConnector> {
+        let mut ctx = try!(SslContext::new(Sslv23).map_err(from_ssl_error));
+        try!(ctx.set_cipher_list("DEFAULT").map_err(from_ssl_error));
+        try!(ctx.set_CA_file(ca_file).map_err(from_ssl_error));
+        ctx.set_verify(SSL_VERIFY_PEER, None);
+        Ok(TlsConnector {
+            ctx: Arc::new(ctx),
+            verify_hostname: true,
+        })
+    }
+
+    /// Creates a connector which accepts any certificate
+    pub fn insecure() -> io::Result<TlsConnector> {
+        let mut ctx = try!(SslContext::new(Sslv23).map_err(from_ssl_error));
+        try!(ctx.set_cipher_list("DEFAULT").map_err(from_ssl_error));
+        ctx.set_verify(SSL_VERIFY_NONE, None);
+        Ok(TlsConnector {
+            ctx: Arc::new(ctx),
+            verify_hostname: false,
+        })
+    }
+
+    /// Creates a connector with a custom `SslContext`, whose own verify settings are used
+    pub fn with_context(ctx: SslContext, verify_hostname: bool) -> TlsConnector {
+        TlsConnector {
+            ctx: Arc::new(ctx),
+            verify_hostname: verify_hostname,
+        }
+    }
+
+    pub fn context(&self) -> &SslContext {
+        &self.ctx
+    }
+
+    /// Performs the client side handshake on `stream`, sending `domain` as SNI
+    ///
+    /// If hostname verification is on, `domain` must match the subject alternative names of the
+    /// server certificate, or its common name if it has none. IP literals are not sent as SNI.
+    pub fn connect<S: Read + Write>(&self, domain: &str, stream: S) -> io::Result<TlsStream<S>> {
+        let ssl = try!(Ssl::new(&self.ctx).map_err(from_ssl_error));
+        if ip_octets(domain).is_none() {
+            try!(ssl.set_hostname(domain).map_err(from_ssl_error));
+        }
+        let stream = try!(SslStream::new_from(ssl, stream).map_err(from_ssl_error));
+        let stream = TlsStream { inner: stream };
+
+        if self.verify_hostname {
+            let cert = match stream.peer_certificate() {
+                Some(cert) => cert,
+                None => return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                                  "server did not send a certificate")),
+            };
+            if !certificate_matches(&cert, domain) {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                          "server certificate does not match the hostname"));
+            }
+        }
+
+        Ok(stream)
+    }
+}
+
+/// An established TLS connection over `S`
+pub struct TlsStream<S> {
+    inner: SslStream<S>,
+}
+
+impl<S: Read + Write> TlsStream<S> {
+    pub fn get_ref(&self) -> &S {
+        self.inner.get_ref()
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        self.inner.get_mut()
+    }
+
+    pub fn ssl(&self) -> &Ssl {
+        self.inner.ssl()
+    }
+
+    /// Certificate presented by the peer, if any
+    pub fn peer_certificate(&self) -> Option<X509> {
+        self.inner.ssl().peer_certificate()
+    }
+
+    /// Identity in the certificate of the peer, if it sent one
+    ///
+    /// With `verify_client_certs` the certificate was verified against the CA bundle during the
+    /// handshake, so the identity can be trusted for authorization.
+    pub fn peer_identity(&self) -> Option<PeerIdentity> {
+        self.peer_certificate().map(|cert| PeerIdentity::from_certificate(&cert))
+    }
+
+    /// Protocol agreed on with ALPN, e.g. `b"http/1.1"`
+    pub fn alpn_protocol(&self) -> Option<Vec<u8>> {
+        self.inner.ssl().selected_alpn_protocol().map(|p| p.to_vec())
+    }
+
+    /// Hostname sent by the client with SNI
+    pub fn server_name(&self) -> Option<String> {
+        self.inner.ssl().get_servername()
+    }
+}
+
+impl TlsStream<TcpStream> {
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.get_ref().peer_addr()
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.get_ref().local_addr()
+    }
+
+    /// Shuts down the underlying socket, without sending a TLS close_notify
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.get_ref().shutdown(how)
+    }
+}
+
+impl<S: Clone> Clone for TlsStream<S> {
+    fn clone(&self) -> TlsStream<S> {
+        TlsStream { inner: self.inner.clone() }
+    }
+}
+
+impl<S: Read + Write> Read for TlsStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl<S: Read + Write> Write for TlsStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// A TLS connection which can be used by several coroutines at once, e.g. to proxy it with
+/// `copy_bidirectional`
+///
+/// OpenSSL does not allow one session to be used by two threads at once, so every use of the
+/// session takes a lock, and a coroutine waiting for it yields to the others. A read waits for the
+/// socket to become readable without holding the lock, so writes go ahead meanwhile, and only
+/// holds it while OpenSSL reads the rest of a record. Half-closing shuts down the socket without
+/// sending a close_notify.
+pub struct SharedTlsStream {
+    tls: Arc<Mutex<TlsStream<TcpStream>>>,
+    /// Another handle to the socket of the session, to wait for it without holding the lock
+    socket: TcpStream,
+}
+
+impl SharedTlsStream {
+    pub fn new(tls: TlsStream<TcpStream>) -> io::Result<SharedTlsStream> {
+        let socket = try!(tls.get_ref().try_clone());
+        Ok(SharedTlsStream {
+            tls: Arc::new(Mutex::new(tls)),
+            socket: socket,
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<TlsStream<TcpStream>> {
+        loop {
+            match self.tls.try_lock() {
+                Ok(tls) => return tls,
+                Err(TryLockError::WouldBlock) => Coroutine::sched(),
+                Err(TryLockError::Poisoned(err)) => return err.into_inner(),
+            }
+        }
+    }
+}
+
+impl Duplex for SharedTlsStream {
+    fn try_clone(&self) -> io::Result<SharedTlsStream> {
+        Ok(SharedTlsStream {
+            tls: self.tls.clone(),
+            socket: try!(self.socket.try_clone()),
+        })
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.socket.shutdown(how)
+    }
+}
+
+impl Read for SharedTlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // Data OpenSSL already decrypted does not make the socket readable
+        if self.lock().ssl().pending() == 0 {
+            try!(Scheduler::current().wait_event(&*self.socket, Interest::readable()));
+        }
+        self.lock().read(buf)
+    }
+}
+
+impl Write for SharedTlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.lock().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.lock().flush()
+    }
+}
+
+/// Subject and subject alternative names of a peer certificate
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PeerIdentity {
+    /// Distinguished name of the subject, e.g. `CN=client,OU=Ops,O=Example,C=US`
+    ///
+    /// Holds the common name, organizational unit, organization, locality, state and country, in
+    /// RFC 4514 order and escaping.
+    pub subject: String,
+    /// Common name of the subject
+    pub common_name: Option<String>,
+    /// Organization of the subject
+    pub organization: Option<String>,
+    /// DNS names and IP addresses in the subject alternative name extension
+    pub alt_names: Vec<String>,
+}
+
+impl PeerIdentity {
+    pub fn from_certificate(cert: &X509) -> PeerIdentity {
+        let name = cert.subject_name();
+
+        let (dns_names, ip_addresses) = subject_alt_names(cert);
+        let mut alt_names = dns_names;
+        alt_names.extend(ip_addresses.iter().map(|ip| format_ip(ip)));
+
+        PeerIdentity {
+            subject: format_subject(&name),
+            common_name: name.text_by_nid(Nid::CN).map(|s| s.to_string()),
+            organization: name.text_by_nid(Nid::O).map(|s| s.to_string()),
+            alt_names: alt_names,
+        }
+    }
+}
+
+/// Makes a server context ask for client certificates and verify them against the CAs in `ca_file`
+///
+/// The names of these CAs are sent with the request, so clients with several certificates can pick
+/// one they issued. If `required` is set, handshakes with clients that send no certificate fail
+/// as well.
+pub fn verify_client_certs(ctx: &mut SslContext, ca_file: &Path, required: bool) -> io::Result<()> {
+    try!(ctx.set_CA_file(ca_file).map_err(from_ssl_error));
+    try!(set_client_ca_list(ctx, ca_file));
+    if required {
+        ctx.set_verify(SSL_VERIFY_PEER | SSL_VERIFY_FAIL_IF_NO_PEER_CERT, None);
+    } else {
+        ctx.set_verify(SSL_VERIFY_PEER, None);
+    }
+    Ok(())
+}
+
+/// Creates a server context with the certificate and private key in the given PEM files
+pub fn server_context(cert: &Path, key: &Path) -> io::Result<SslContext> {
+    let mut ctx = try!(SslContext::new(Sslv23).map_err(from_ssl_error));
+    try!(ctx.set_cipher_list("DEFAULT").map_err(from_ssl_error));
+    try!(ctx.set_certificate_file(cert, X509FileType::PEM).map_err(from_ssl_error));
+    try!(ctx.set_private_key_file(key, X509FileType::PEM).map_err(from_ssl_error));
+    try!(ctx.check_private_key().map_err(from_ssl_error));
+    ctx.set_verify(SSL_VERIFY_NONE, None);
+    Ok(ctx)
+}
+
+// Sends the subject names of the certificates in `ca_file` in the certificate request
+fn set_client_ca_list(ctx: &mut SslContext, ca_file: &Path) -> io::Result<()> {
+    let file = try!(CString::new(ca_file.as_os_str().as_bytes()).map_err(|_| {
+        io::Error::new(io::ErrorKind::InvalidInput, "CA file path contains a NUL byte")
+    }));
+
+    unsafe {
+        let names = SSL_load_client_CA_file(file.as_ptr());
+        if names.is_null() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "no CA names in the CA file"));
+        }
+        // `SslContext` only holds the `SSL_CTX` pointer, which takes ownership of `names`
+        let raw = *(ctx as *mut SslContext as *mut *mut c_void);
+        SSL_CTX_set_client_CA_list(raw, names);
+    }
+    Ok(())
+}
+
+// Distinguished name of a certificate subject, most specific attribute first (RFC 4514)
+fn format_subject(name: &X509Name) -> String {
+    let attributes = vec![
+        ("CN", name.text_by_nid(Nid::CN).map(|s| s.to_string())),
+        ("OU", name.text_by_nid(Nid::OU).map(|s| s.to_string())),
+        ("O", name.text_by_nid(Nid::O).map(|s| s.to_string())),
+        ("L", name.text_by_nid(Nid::L).map(|s| s.to_string())),
+        ("ST", name.text_by_nid(Nid::ST).map(|s| s.to_string())),
+        ("C", name.text_by_nid(Nid::C).map(|s| s.to_string())),
+    ];
+
+    attributes.into_iter()
+              .filter_map(|(key, value)| value.map(|v| format!("{}={}", key, escape_dn_value(&v))))
+              .collect::<Vec<String>>()
+              .join(",")
+}
+
+// Escapes an attribute value of a distinguished name string (RFC 4514, section 2.4)
+fn escape_dn_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    let last = value.chars().count().saturating_sub(1);
+    for (idx, c) in value.chars().enumerate() {
+        let special = match c {
+            ',' | '+' | '"' | '\\' | '<' | '>' | ';' => true,
+            '#' | ' ' if idx == 0 => true,
+            ' ' if idx == last => true,
+            _ => false,
+        };
+        if special {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+// SNI callback, switches the connection to the context of the requested hostname
+fn select_context(ssl: &mut Ssl, _: &mut i32, hosts: &Arc<SniMap>) -> i32 {
+    if let Some(name) = ssl.get_servername() {
+        match hosts.get(&name.to_ascii_lowercase()) {
+            Some(ctx) => {
+                debug!("SNI: selected context for {}", name);
+                ssl.set_ssl_context(ctx);
+            },
+            None => debug!("SNI: no context for {}, using the default", name),
+        }
+    }
+    SSL_TLSEXT_ERR_OK
+}
+
+// DNS names and raw IP addresses in the subject alternative name extension of `cert`
+fn subject_alt_names(cert: &X509) -> (Vec<String>, Vec<Vec<u8>>) {
+    let mut dns_names = Vec::new();
+    let mut ip_addresses = Vec::new();
+    if let Some(names) = cert.subject_alt_names() {
+        for name in names.iter() {
+            if let Some(dns) = name.dns() {
+                dns_names.push(dns.to_owned());
+            } else if let Some(ip) = name.ipaddress() {
+                ip_addresses.push(ip.to_vec());
+            }
+        }
+    }
+    (dns_names, ip_addresses)
+}
+
+// Checks `cert` against the host the client connected to, as in RFC 6125
+//
+// An IP literal only matches iPAddress entries. A hostname matches the dNSName entries, or the
+// common name if the certificate has none.
+fn certificate_matches(cert: &X509, domain: &str) -> bool {
+    let (dns_names, ip_addresses) = subject_alt_names(cert);
+
+    if let Some(ip) = ip_octets(domain) {
+        return ip_addresses.iter().any(|addr| *addr == ip);
+    }
+
+    if !dns_names.is_empty() {
+        return dns_names.iter().any(|name| dns_name_matches(name, domain));
+    }
+
+    match cert.subject_name().text_by_nid(Nid::CN) {
+        Some(cn) => dns_name_matches(&cn, domain),
+        None => false,
+    }
+}
+
+// Compares a name from a certificate with `domain`, a leading `*.` matches exactly one label
+fn dns_name_matches(pattern: &str, domain: &str) -> bool {
+    let pattern = pattern.to_ascii_lowercase();
+    let domain = domain.to_ascii_lowercase();
+    if pattern.starts_with("*.") {
+        match domain.find('.') {
+            Some(idx) => idx > 0 && &domain[idx..] == &pattern[1..],
+            None => false,
+        }
+    } else {
+        pattern == domain
+    }
+}
+
+// Octets of `host` if it is an IPv4 or IPv6 literal, which may be in brackets
+fn ip_octets(host: &str) -> Option<Vec<u8>> {
+    let host = if host.starts_with('[') && host.ends_with(']') {
+        &host[1..host.len() - 1]
+    } else {
+        host
+    };
+
+    if let Ok(ip) = host.parse::<Ipv4Addr>() {
+        return Some(ip.octets().to_vec());
+    }
+    if let Ok(ip) = host.parse::<Ipv6Addr>() {
+        let mut octets = Vec::with_capacity(16);
+        for seg in ip.segments().iter() {
+            octets.push((*seg >> 8) as u8);
+            octets.push(*seg as u8);
+        }
+        return Some(octets);
+    }
+    None
+}
+
+fn format_ip(ip: &[u8]) -> String {
+    match ip.len() {
+        4 => Ipv4Addr::new(ip[0], ip[1], ip[2], ip[3]).to_string(),
+        16 => {
+            let mut seg = [0u16; 8];
+            for (i, c) in ip.chunks(2).enumerate() {
+                seg[i] = (c[0] as u16) << 8 | c[1] as u16;
+            }
+            Ipv6Addr::new(seg[0], seg[1], seg[2], seg[3], seg[4], seg[5], seg[6], seg[7]).to_string()
+        },
+        _ => String::new(),
+    }
+}
+
+/// Converts an `SslError` to an `io::Error`, keeping errors of the underlying stream as they are
+pub fn from_ssl_error(err: SslError) -> io::Error {
+    match err {
+        SslIoError(e) => e,
+        e => io::Error::new(io::ErrorKind::ConnectionAborted, e),
+    }
+}
@@ -1,12 +1,21 @@
-
 use std::ops::{Deref, DerefMut};
 use std::io;
-use std::net::SocketAddr;
+use std::net::{ToSocketAddrs, SocketAddr, Ipv4Addr, Ipv6Addr};
+use std::os::unix::io::{AsRawFd, RawFd};
+#[cfg(any(target_os = "linux",
+          target_os = "android"))]
+use std::ptr;
+
+use libc::{self, c_int, c_void, size_t};
+#[cfg(any(target_os = "linux",
+          target_os = "android"))]
+use libc::{c_uint, ssize_t, socklen_t};
 
 use mio::Interest;
-use mio::buf::{Buf, MutBuf};
 
-use scheduler::Scheduler;
+use super::sys;
+use super::sockopt;
+use scheduler::retry_nonblocking;
 
 pub struct UdpSocket(::mio::udp::UdpSocket);
 
@@ -21,6 +30,11 @@ impl UdpSocket {
         Ok(UdpSocket(try!(::mio::udp::UdpSocket::v6())))
     }
 
+    /// Returns a new UDP socket bound to the first address `addr` resolves to
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<UdpSocket> {
+        super::each_addr(addr, UdpSocket::bound)
+    }
+
     pub fn bound(addr: &SocketAddr) -> io::Result<UdpSocket> {
         Ok(UdpSocket(try!(::mio::udp::UdpSocket::bound(addr))))
     }
@@ -29,51 +43,301 @@ impl UdpSocket {
         Ok(UdpSocket(try!(self.0.try_clone())))
     }
 
-    pub fn send_to<B: Buf>(&self, buf: &mut B, target: &SocketAddr) -> io::Result<Option<()>> {
-        match try!(self.0.send_to(buf, target)) {
-            None => {
-                debug!("UdpSocket send_to WOULDBLOCK");
-            },
-            Some(..) => {
-                return Ok(Some(()));
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.0.local_addr()
+    }
+
+    /// Connects this socket to a remote address, so that `send` and `recv` can be used
+    ///
+    /// Datagrams from other addresses are dropped by the kernel.
+    pub fn connect<A: ToSocketAddrs>(&self, addr: A) -> io::Result<()> {
+        let fd = self.as_raw_fd();
+        super::each_addr(addr, |a| {
+            let (raw, len) = sys::sockaddr_from(a);
+            match unsafe { libc::connect(fd, raw.as_ptr(), len) } {
+                -1 => Err(io::Error::last_os_error()),
+                _ => Ok(()),
             }
-        }
+        })
+    }
 
-        try!(Scheduler::current().wait_event(&self.0, Interest::writable()));
+    /// Sends a datagram to `addr`, blocking the coroutine until the socket is writable
+    pub fn send_to<A: ToSocketAddrs>(&self, buf: &[u8], addr: A) -> io::Result<usize> {
+        let addr = match try!(addr.to_socket_addrs()).next() {
+            Some(addr) => addr,
+            None => return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                              "could not resolve to any addresses")),
+        };
+
+        retry_nonblocking(&self.0, Interest::writable(), || self.try_send_to(buf, &addr))
+    }
 
-        match try!(self.0.send_to(buf, target)) {
-            None => {
-                panic!("UdpSocket send_to WOULDBLOCK");
-            },
-            Some(..) => {
-                return Ok(Some(()));
+    /// Receives a datagram, blocking the coroutine until one arrives
+    ///
+    /// Returns the length of the datagram and the address it comes from. The rest of a datagram
+    /// larger than `buf` is discarded.
+    pub fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        self.recv_from_flags(buf, 0)
+    }
+
+    /// Same as `recv_from`, but leaves the datagram in the receive queue
+    pub fn peek_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        self.recv_from_flags(buf, sys::MSG_PEEK)
+    }
+
+    /// Sends a datagram to the connected address, blocking the coroutine until the socket is writable
+    pub fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        let fd = self.as_raw_fd();
+        retry_nonblocking(&self.0, Interest::writable(), || unsafe {
+            sys::cvt(libc::send(fd, buf.as_ptr() as *const c_void, buf.len() as size_t, 0))
+        })
+    }
+
+    /// Receives a datagram from the connected address, blocking the coroutine until one arrives
+    pub fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let fd = self.as_raw_fd();
+        retry_nonblocking(&self.0, Interest::readable(), || unsafe {
+            sys::cvt(libc::recv(fd, buf.as_mut_ptr() as *mut c_void, buf.len() as size_t, 0))
+        })
+    }
+
+    /// Allows sending datagrams to broadcast addresses (`SO_BROADCAST`)
+    pub fn set_broadcast(&self, broadcast: bool) -> io::Result<()> {
+        sockopt::set_broadcast(self.as_raw_fd(), broadcast)
+    }
+
+    /// Gets the value of `SO_BROADCAST`
+    pub fn broadcast(&self) -> io::Result<bool> {
+        sockopt::broadcast(self.as_raw_fd())
+    }
+
+    /// Joins the IPv4 multicast group `multiaddr` on the interface with the address `interface`
+    ///
+    /// `Ipv4Addr::new(0, 0, 0, 0)` lets the kernel choose the interface.
+    pub fn join_multicast_v4(&self, multiaddr: &Ipv4Addr, interface: &Ipv4Addr) -> io::Result<()> {
+        sockopt::join_multicast_v4(self.as_raw_fd(), multiaddr, interface)
+    }
+
+    /// Leaves an IPv4 multicast group joined with `join_multicast_v4`
+    pub fn leave_multicast_v4(&self, multiaddr: &Ipv4Addr, interface: &Ipv4Addr) -> io::Result<()> {
+        sockopt::leave_multicast_v4(self.as_raw_fd(), multiaddr, interface)
+    }
+
+    /// Joins the IPv6 multicast group `multiaddr` on the interface with the index `interface`
+    ///
+    /// Index 0 lets the kernel choose the interface.
+    pub fn join_multicast_v6(&self, multiaddr: &Ipv6Addr, interface: u32) -> io::Result<()> {
+        sockopt::join_multicast_v6(self.as_raw_fd(), multiaddr, interface)
+    }
+
+    /// Leaves an IPv6 multicast group joined with `join_multicast_v6`
+    pub fn leave_multicast_v6(&self, multiaddr: &Ipv6Addr, interface: u32) -> io::Result<()> {
+        sockopt::leave_multicast_v6(self.as_raw_fd(), multiaddr, interface)
+    }
+
+    /// Sets the interface outgoing IPv4 multicast datagrams are sent from (`IP_MULTICAST_IF`)
+    pub fn set_multicast_if_v4(&self, interface: &Ipv4Addr) -> io::Result<()> {
+        sockopt::set_multicast_if_v4(self.as_raw_fd(), interface)
+    }
+
+    /// Sets the time-to-live of outgoing IPv4 multicast datagrams (`IP_MULTICAST_TTL`)
+    pub fn set_multicast_ttl_v4(&self, ttl: u32) -> io::Result<()> {
+        sockopt::set_multicast_ttl_v4(self.as_raw_fd(), ttl)
+    }
+
+    /// Gets the value of `IP_MULTICAST_TTL`
+    pub fn multicast_ttl_v4(&self) -> io::Result<u32> {
+        sockopt::multicast_ttl_v4(self.as_raw_fd())
+    }
+
+    /// Sets whether outgoing IPv4 multicast datagrams are looped back to the local host (`IP_MULTICAST_LOOP`)
+    pub fn set_multicast_loop_v4(&self, on: bool) -> io::Result<()> {
+        sockopt::set_multicast_loop_v4(self.as_raw_fd(), on)
+    }
+
+    /// Gets the value of `IP_MULTICAST_LOOP`
+    pub fn multicast_loop_v4(&self) -> io::Result<bool> {
+        sockopt::multicast_loop_v4(self.as_raw_fd())
+    }
+
+    /// Sets the hop limit of outgoing IPv6 multicast datagrams (`IPV6_MULTICAST_HOPS`)
+    pub fn set_multicast_hops_v6(&self, hops: u32) -> io::Result<()> {
+        sockopt::set_multicast_hops_v6(self.as_raw_fd(), hops)
+    }
+
+    /// Gets the value of `IPV6_MULTICAST_HOPS`
+    pub fn multicast_hops_v6(&self) -> io::Result<u32> {
+        sockopt::multicast_hops_v6(self.as_raw_fd())
+    }
+
+    /// Sets whether outgoing IPv6 multicast datagrams are looped back to the local host (`IPV6_MULTICAST_LOOP`)
+    pub fn set_multicast_loop_v6(&self, on: bool) -> io::Result<()> {
+        sockopt::set_multicast_loop_v6(self.as_raw_fd(), on)
+    }
+
+    /// Gets the value of `IPV6_MULTICAST_LOOP`
+    pub fn multicast_loop_v6(&self) -> io::Result<bool> {
+        sockopt::multicast_loop_v6(self.as_raw_fd())
+    }
+
+    /// Receives up to `bufs.len()` datagrams at once, blocking the coroutine until at least one arrives
+    ///
+    /// The `n`-th datagram is written into `bufs[n]`. Returns the length and the source address of
+    /// every datagram received. It is a single `recvmmsg` call on Linux.
+    #[cfg(any(target_os = "linux",
+              target_os = "android"))]
+    pub fn recv_batch(&self, bufs: &mut [&mut [u8]]) -> io::Result<Vec<(usize, SocketAddr)>> {
+        let mut addrs = vec![sys::sockaddr_storage::new(); bufs.len()];
+        let mut iovecs = bufs.iter_mut().map(|buf| {
+            sys::iovec {
+                iov_base: buf.as_mut_ptr() as *mut c_void,
+                iov_len: buf.len() as size_t,
+            }
+        }).collect::<Vec<sys::iovec>>();
+        let mut msgs = iovecs.iter_mut().zip(addrs.iter_mut()).map(|(iov, addr)| {
+            sys::mmsghdr {
+                msg_hdr: sys::msghdr {
+                    msg_name: addr.as_mut_ptr() as *mut c_void,
+                    msg_namelen: sys::sockaddr_storage::capacity(),
+                    msg_iov: iov as *mut sys::iovec,
+                    msg_iovlen: 1,
+                    msg_control: ptr::null_mut(),
+                    msg_controllen: 0,
+                    msg_flags: 0,
+                },
+                msg_len: 0,
             }
+        }).collect::<Vec<sys::mmsghdr>>();
+
+        let fd = self.as_raw_fd();
+        let received = try!(retry_nonblocking(&self.0, Interest::readable(), || unsafe {
+            sys::cvt(sys::recvmmsg(fd, msgs.as_mut_ptr(), msgs.len() as c_uint, 0, ptr::null_mut()) as ssize_t)
+        }));
+        debug!("UdpSocket recv_batch received {} datagrams", received);
+
+        let mut result = Vec::with_capacity(received);
+        for (idx, msg) in msgs[..received].iter().enumerate() {
+            let addr = try!(sys::sockaddr_to(&addrs[idx], msg.msg_hdr.msg_namelen));
+            result.push((msg.msg_len as usize, addr));
         }
+        Ok(result)
     }
 
-    pub fn recv_from<B: MutBuf>(&self, buf: &mut B) -> io::Result<Option<SocketAddr>> {
-        match try!(self.0.recv_from(buf)) {
-            None => {
-                debug!("UdpSocket recv_from WOULDBLOCK");
-            },
-            Some(addr) => {
-                return Ok(Some(addr));
+    /// Receives up to `bufs.len()` datagrams at once, blocking the coroutine until at least one arrives
+    ///
+    /// The `n`-th datagram is written into `bufs[n]`. Returns the length and the source address of
+    /// every datagram received.
+    #[cfg(not(any(target_os = "linux",
+                  target_os = "android")))]
+    pub fn recv_batch(&self, bufs: &mut [&mut [u8]]) -> io::Result<Vec<(usize, SocketAddr)>> {
+        let mut result = Vec::with_capacity(bufs.len());
+        for buf in bufs.iter_mut() {
+            if result.is_empty() {
+                result.push(try!(self.recv_from(buf)));
+                continue;
+            }
+
+            match self.try_recv_from_flags(buf, 0) {
+                Ok(received) => result.push(received),
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) => return Err(err),
             }
         }
+        Ok(result)
+    }
+
+    /// Sends several datagrams at once, blocking the coroutine until the socket is writable
+    ///
+    /// Returns the number of datagrams sent, which may be less than `msgs.len()`. It is a single
+    /// `sendmmsg` call on Linux.
+    #[cfg(any(target_os = "linux",
+              target_os = "android"))]
+    pub fn send_batch(&self, msgs: &[(&[u8], SocketAddr)]) -> io::Result<usize> {
+        let mut addrs = msgs.iter().map(|&(_, ref addr)| sys::sockaddr_from(addr))
+                            .collect::<Vec<(sys::sockaddr_storage, socklen_t)>>();
+        let mut iovecs = msgs.iter().map(|&(buf, _)| {
+            sys::iovec {
+                iov_base: buf.as_ptr() as *mut c_void,
+                iov_len: buf.len() as size_t,
+            }
+        }).collect::<Vec<sys::iovec>>();
+        let mut hdrs = iovecs.iter_mut().zip(addrs.iter_mut()).map(|(iov, &mut (ref mut addr, len))| {
+            sys::mmsghdr {
+                msg_hdr: sys::msghdr {
+                    msg_name: addr.as_mut_ptr() as *mut c_void,
+                    msg_namelen: len,
+                    msg_iov: iov as *mut sys::iovec,
+                    msg_iovlen: 1,
+                    msg_control: ptr::null_mut(),
+                    msg_controllen: 0,
+                    msg_flags: 0,
+                },
+                msg_len: 0,
+            }
+        }).collect::<Vec<sys::mmsghdr>>();
 
-        try!(Scheduler::current().wait_event(&self.0, Interest::readable()));
+        let fd = self.as_raw_fd();
+        let sent = try!(retry_nonblocking(&self.0, Interest::writable(), || unsafe {
+            sys::cvt(sys::sendmmsg(fd, hdrs.as_mut_ptr(), hdrs.len() as c_uint, 0) as ssize_t)
+        }));
+        debug!("UdpSocket send_batch sent {} datagrams", sent);
 
-        match try!(self.0.recv_from(buf)) {
-            None => {
-                panic!("UdpSocket recv_from WOULDBLOCK");
-            },
-            Some(addr) => {
-                return Ok(Some(addr));
+        Ok(sent)
+    }
+
+    /// Sends several datagrams at once, blocking the coroutine until the socket is writable
+    ///
+    /// Returns the number of datagrams sent, which may be less than `msgs.len()`.
+    #[cfg(not(any(target_os = "linux",
+                  target_os = "android")))]
+    pub fn send_batch(&self, msgs: &[(&[u8], SocketAddr)]) -> io::Result<usize> {
+        for (idx, &(buf, ref addr)) in msgs.iter().enumerate() {
+            if idx == 0 {
+                try!(self.send_to(buf, addr));
+                continue;
             }
+
+            match self.try_send_to(buf, addr) {
+                Ok(..) => (),
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(idx),
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(msgs.len())
+    }
+
+    fn recv_from_flags(&self, buf: &mut [u8], flags: c_int) -> io::Result<(usize, SocketAddr)> {
+        retry_nonblocking(&self.0, Interest::readable(), || self.try_recv_from_flags(buf, flags))
+    }
+
+    fn try_recv_from_flags(&self, buf: &mut [u8], flags: c_int) -> io::Result<(usize, SocketAddr)> {
+        let mut raw = sys::sockaddr_storage::new();
+        let mut raw_len = sys::sockaddr_storage::capacity();
+        let len = try!(unsafe {
+            sys::cvt(libc::recvfrom(self.as_raw_fd(), buf.as_mut_ptr() as *mut c_void, buf.len() as size_t,
+                                    flags, raw.as_mut_ptr(), &mut raw_len))
+        });
+
+        Ok((len, try!(sys::sockaddr_to(&raw, raw_len))))
+    }
+
+    fn try_send_to(&self, buf: &[u8], addr: &SocketAddr) -> io::Result<usize> {
+        let (raw, len) = sys::sockaddr_from(addr);
+        unsafe {
+            sys::cvt(libc::sendto(self.as_raw_fd(), buf.as_ptr() as *const c_void, buf.len() as size_t, 0,
+                                  raw.as_ptr(), len))
         }
     }
 }
 
+impl AsRawFd for UdpSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
+impl_socket_opts!(UdpSocket);
+
 impl Deref for UdpSocket {
     type Target = ::mio::udp::UdpSocket;
 
@@ -0,0 +1,593 @@
+use std::io;
+use std::mem;
+use std::cmp;
+use std::ptr;
+use std::ffi::OsStr;
+use std::net::Shutdown;
+use std::path::{Path, PathBuf};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use libc::{self, c_int, c_void, size_t, socklen_t};
+
+use mio::{Interest, Io};
+
+use scheduler::Scheduler;
+use super::{sys, sockopt, Duplex};
+use scheduler::retry_nonblocking;
+
+/// Address of a Unix domain socket
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum UnixAddr {
+    /// Address of an unbound socket
+    Unnamed,
+    /// A path in the filesystem
+    Path(PathBuf),
+    /// A name in the abstract namespace (Linux only), without the leading NUL byte
+    Abstract(Vec<u8>),
+}
+
+impl UnixAddr {
+    fn to_raw(&self) -> io::Result<(sys::sockaddr_un, socklen_t)> {
+        let mut raw: sys::sockaddr_un = unsafe { mem::zeroed() };
+        raw.sun_family = libc::AF_UNIX as sys::sa_family_t;
+
+        let offset = sun_path_offset(&raw);
+        let len = match *self {
+            UnixAddr::Unnamed => offset,
+            UnixAddr::Path(ref path) => {
+                let bytes = path.as_os_str().as_bytes();
+                // Keep room for the NUL terminator
+                if bytes.len() >= raw.sun_path.len() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                              "path must be shorter than SUN_LEN"));
+                }
+                for (dst, src) in raw.sun_path.iter_mut().zip(bytes.iter()) {
+                    *dst = *src;
+                }
+                offset + bytes.len() + 1
+            },
+            UnixAddr::Abstract(ref name) => {
+                if name.len() + 1 > raw.sun_path.len() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                              "abstract name must be shorter than SUN_LEN"));
+                }
+                for (dst, src) in raw.sun_path[1..].iter_mut().zip(name.iter()) {
+                    *dst = *src;
+                }
+                offset + 1 + name.len()
+            }
+        };
+
+        init_sun_len(&mut raw, len);
+        Ok((raw, len as socklen_t))
+    }
+
+    fn from_raw(raw: &sys::sockaddr_un, len: socklen_t) -> UnixAddr {
+        let offset = sun_path_offset(raw);
+        let len = len as usize;
+
+        if len <= offset {
+            return UnixAddr::Unnamed;
+        }
+
+        let path = &raw.sun_path[..cmp::min(len - offset, raw.sun_path.len())];
+        if path[0] == 0 {
+            UnixAddr::Abstract(path[1..].to_vec())
+        } else {
+            let end = path.iter().position(|b| *b == 0).unwrap_or(path.len());
+            UnixAddr::Path(PathBuf::from(OsStr::from_bytes(&path[..end])))
+        }
+    }
+}
+
+fn sun_path_offset(raw: &sys::sockaddr_un) -> usize {
+    let base = raw as *const sys::sockaddr_un as usize;
+    let path = &raw.sun_path as *const _ as usize;
+    path - base
+}
+
+#[cfg(any(target_os = "linux",
+          target_os = "android"))]
+fn init_sun_len(_: &mut sys::sockaddr_un, _: usize) {}
+
+#[cfg(any(target_os = "macos",
+          target_os = "freebsd",
+          target_os = "dragonfly",
+          target_os = "ios",
+          target_os = "bitrig",
+          target_os = "openbsd"))]
+fn init_sun_len(raw: &mut sys::sockaddr_un, len: usize) {
+    raw.sun_len = len as u8;
+}
+
+// A non-blocking Unix domain socket, shared by the stream, listener and datagram wrappers
+struct Socket(Io);
+
+impl Socket {
+    fn new(ty: c_int) -> io::Result<Socket> {
+        let fd = unsafe { libc::socket(libc::AF_UNIX, ty, 0) };
+        if fd == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Socket::from_fd(fd)
+    }
+
+    fn pair(ty: c_int) -> io::Result<(Socket, Socket)> {
+        let mut fds = [0; 2];
+        if unsafe { sys::socketpair(libc::AF_UNIX, ty, 0, fds.as_mut_ptr()) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let a = try!(Socket::from_fd(fds[0]));
+        let b = try!(Socket::from_fd(fds[1]));
+        Ok((a, b))
+    }
+
+    fn from_fd(fd: RawFd) -> io::Result<Socket> {
+        // Owns the fd from now on, it is closed on error
+        let sock = Socket(Io::from(fd));
+        try!(sys::set_nonblocking_cloexec(fd));
+        Ok(sock)
+    }
+
+    fn try_clone(&self) -> io::Result<Socket> {
+        let fd = unsafe { libc::dup(self.as_raw_fd()) };
+        if fd == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Socket::from_fd(fd)
+    }
+
+    fn bind(&self, addr: &UnixAddr) -> io::Result<()> {
+        let (raw, len) = try!(addr.to_raw());
+        let ret = unsafe {
+            libc::bind(self.as_raw_fd(), &raw as *const sys::sockaddr_un as *const libc::sockaddr, len)
+        };
+
+        if ret == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn listen(&self, backlog: usize) -> io::Result<()> {
+        if unsafe { libc::listen(self.as_raw_fd(), backlog as c_int) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn connect(&self, addr: &UnixAddr) -> io::Result<()> {
+        let (raw, len) = try!(addr.to_raw());
+        let fd = self.as_raw_fd();
+
+        // Unix sockets report a full backlog with EAGAIN, and may be retried when writable
+        let result = retry_nonblocking(&self.0, Interest::writable(), || {
+            let ret = unsafe {
+                libc::connect(fd, &raw as *const sys::sockaddr_un as *const libc::sockaddr, len)
+            };
+            match ret {
+                -1 => Err(io::Error::last_os_error()),
+                _ => Ok(()),
+            }
+        });
+
+        match result {
+            Err(ref err) if err.raw_os_error() == Some(libc::EINPROGRESS) => {
+                debug!("UnixSocket connect in progress");
+            },
+            result => return result,
+        }
+
+        try!(Scheduler::current().wait_event(&self.0, Interest::writable()));
+        match try!(sockopt::take_error(&self.0)) {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    fn accept(&self) -> io::Result<(Socket, UnixAddr)> {
+        let fd = self.as_raw_fd();
+        let (client, raw, len) = try!(retry_nonblocking(&self.0, Interest::readable(), || {
+            let mut raw: sys::sockaddr_un = unsafe { mem::zeroed() };
+            let mut len = mem::size_of::<sys::sockaddr_un>() as socklen_t;
+            let client = unsafe {
+                libc::accept(fd, &mut raw as *mut sys::sockaddr_un as *mut libc::sockaddr, &mut len)
+            };
+            match client {
+                -1 => Err(io::Error::last_os_error()),
+                client => Ok((client, raw, len)),
+            }
+        }));
+
+        Ok((try!(Socket::from_fd(client)), UnixAddr::from_raw(&raw, len)))
+    }
+
+    fn local_addr(&self) -> io::Result<UnixAddr> {
+        let mut raw: sys::sockaddr_un = unsafe { mem::zeroed() };
+        let mut len = mem::size_of::<sys::sockaddr_un>() as socklen_t;
+        let ret = unsafe {
+            libc::getsockname(self.as_raw_fd(), &mut raw as *mut sys::sockaddr_un as *mut libc::sockaddr,
+                              &mut len)
+        };
+
+        if ret == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(UnixAddr::from_raw(&raw, len))
+    }
+
+    fn peer_addr(&self) -> io::Result<UnixAddr> {
+        let mut raw: sys::sockaddr_un = unsafe { mem::zeroed() };
+        let mut len = mem::size_of::<sys::sockaddr_un>() as socklen_t;
+        let ret = unsafe {
+            libc::getpeername(self.as_raw_fd(), &mut raw as *mut sys::sockaddr_un as *mut libc::sockaddr,
+                              &mut len)
+        };
+
+        if ret == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(UnixAddr::from_raw(&raw, len))
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        let how = match how {
+            Shutdown::Read => libc::SHUT_RD,
+            Shutdown::Write => libc::SHUT_WR,
+            Shutdown::Both => libc::SHUT_RDWR,
+        };
+
+        match unsafe { libc::shutdown(self.as_raw_fd(), how) } {
+            -1 => {
+                let err = io::Error::last_os_error();
+                match err.kind() {
+                    io::ErrorKind::NotConnected => Ok(()),
+                    _ => Err(err),
+                }
+            },
+            _ => Ok(()),
+        }
+    }
+
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let fd = self.as_raw_fd();
+        retry_nonblocking(&self.0, Interest::readable(), || unsafe {
+            sys::cvt(libc::recv(fd, buf.as_mut_ptr() as *mut c_void, buf.len() as size_t, 0))
+        })
+    }
+
+    fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        let fd = self.as_raw_fd();
+        retry_nonblocking(&self.0, Interest::writable(), || unsafe {
+            sys::cvt(libc::send(fd, buf.as_ptr() as *const c_void, buf.len() as size_t, sys::MSG_NOSIGNAL))
+        })
+    }
+
+    // Sends `buf` together with `fds` as `SCM_RIGHTS` ancillary data, to `addr` if it is given
+    fn send_msg(&self, buf: &[u8], fds: &[RawFd], addr: Option<&UnixAddr>) -> io::Result<usize> {
+        let (mut raw, raw_len) = match addr {
+            Some(addr) => try!(addr.to_raw()),
+            None => (unsafe { mem::zeroed() }, 0),
+        };
+
+        let mut iov = sys::iovec {
+            iov_base: buf.as_ptr() as *mut c_void,
+            iov_len: buf.len() as size_t,
+        };
+
+        let fds_len = fds.len() * mem::size_of::<RawFd>();
+        let space = if fds.is_empty() { 0 } else { sys::cmsg_space(fds_len) };
+        // u64 keeps the control buffer aligned for cmsghdr
+        let mut control = vec![0u64; (space + 7) / 8];
+
+        if !fds.is_empty() {
+            let cmsg = control.as_mut_ptr() as *mut sys::cmsghdr;
+            unsafe {
+                (*cmsg).cmsg_len = sys::cmsg_len(fds_len) as _;
+                (*cmsg).cmsg_level = libc::SOL_SOCKET;
+                (*cmsg).cmsg_type = sys::SCM_RIGHTS;
+                ptr::copy_nonoverlapping(fds.as_ptr() as *const u8, sys::cmsg_data(cmsg), fds_len);
+            }
+        }
+
+        let msg = sys::msghdr {
+            msg_name: if addr.is_some() { &mut raw as *mut sys::sockaddr_un as *mut c_void } else { ptr::null_mut() },
+            msg_namelen: raw_len,
+            msg_iov: &mut iov,
+            msg_iovlen: 1,
+            msg_control: if space == 0 { ptr::null_mut() } else { control.as_mut_ptr() as *mut c_void },
+            msg_controllen: space as _,
+            msg_flags: 0,
+        };
+
+        let fd = self.as_raw_fd();
+        retry_nonblocking(&self.0, Interest::writable(), || unsafe {
+            sys::cvt(sys::sendmsg(fd, &msg, sys::MSG_NOSIGNAL))
+        })
+    }
+
+    // Receives into `buf` and stores the file descriptors of the `SCM_RIGHTS` ancillary data
+    // into `fds`. Returns the number of bytes, the number of file descriptors and the source address.
+    fn recv_msg(&self, buf: &mut [u8], fds: &mut [RawFd]) -> io::Result<(usize, usize, UnixAddr)> {
+        let mut raw: sys::sockaddr_un = unsafe { mem::zeroed() };
+
+        let mut iov = sys::iovec {
+            iov_base: buf.as_mut_ptr() as *mut c_void,
+            iov_len: buf.len() as size_t,
+        };
+
+        let space = if fds.is_empty() { 0 } else { sys::cmsg_space(fds.len() * mem::size_of::<RawFd>()) };
+        let mut control = vec![0u64; (space + 7) / 8];
+
+        let mut msg = sys::msghdr {
+            msg_name: &mut raw as *mut sys::sockaddr_un as *mut c_void,
+            msg_namelen: mem::size_of::<sys::sockaddr_un>() as socklen_t,
+            msg_iov: &mut iov,
+            msg_iovlen: 1,
+            msg_control: if space == 0 { ptr::null_mut() } else { control.as_mut_ptr() as *mut c_void },
+            msg_controllen: space as _,
+            msg_flags: 0,
+        };
+
+        let fd = self.as_raw_fd();
+        let len = try!(retry_nonblocking(&self.0, Interest::readable(), || unsafe {
+            sys::cvt(sys::recvmsg(fd, &mut msg, sys::MSG_CMSG_CLOEXEC))
+        }));
+
+        if msg.msg_flags & sys::MSG_CTRUNC != 0 {
+            debug!("recv_msg: control data truncated, the descriptors which did not fit were discarded");
+        }
+
+        // The control buffer is rounded up, so the kernel may install more descriptors than
+        // asked for. The ones which do not fit in `fds` are closed here.
+        let mut received = 0;
+        if space != 0 && msg.msg_controllen as usize >= sys::cmsg_len(0) {
+            let cmsg = control.as_mut_ptr() as *mut sys::cmsghdr;
+            unsafe {
+                if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == sys::SCM_RIGHTS {
+                    let data_len = (*cmsg).cmsg_len as usize - sys::cmsg_len(0);
+                    let data = sys::cmsg_data(cmsg) as *const RawFd;
+                    let count = data_len / mem::size_of::<RawFd>();
+                    received = cmp::min(count, fds.len());
+                    ptr::copy_nonoverlapping(data as *const u8, fds.as_mut_ptr() as *mut u8,
+                                             received * mem::size_of::<RawFd>());
+                    for i in received..count {
+                        libc::close(*data.offset(i as isize));
+                    }
+                }
+            }
+        }
+
+        Ok((len, received, UnixAddr::from_raw(&raw, msg.msg_namelen)))
+    }
+}
+
+impl AsRawFd for Socket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
+pub struct UnixListener(Socket);
+
+impl UnixListener {
+    /// Creates a listener bound to the path `path`
+    pub fn bind<P: AsRef<Path>>(path: P) -> io::Result<UnixListener> {
+        UnixListener::bind_addr(&UnixAddr::Path(path.as_ref().to_path_buf()))
+    }
+
+    /// Creates a listener bound to `addr`, which may be in the abstract namespace
+    pub fn bind_addr(addr: &UnixAddr) -> io::Result<UnixListener> {
+        let sock = try!(Socket::new(libc::SOCK_STREAM));
+        try!(sock.bind(addr));
+        try!(sock.listen(128));
+        Ok(UnixListener(sock))
+    }
+
+    /// Accepts a new connection, blocking the coroutine until there is one
+    pub fn accept(&self) -> io::Result<(UnixStream, UnixAddr)> {
+        let (sock, addr) = try!(self.0.accept());
+        Ok((UnixStream(sock), addr))
+    }
+
+    pub fn local_addr(&self) -> io::Result<UnixAddr> {
+        self.0.local_addr()
+    }
+
+    pub fn try_clone(&self) -> io::Result<UnixListener> {
+        Ok(UnixListener(try!(self.0.try_clone())))
+    }
+}
+
+impl AsRawFd for UnixListener {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
+pub struct UnixStream(Socket);
+
+impl UnixStream {
+    /// Connects to the socket at `path`, blocking the coroutine until it is connected
+    pub fn connect<P: AsRef<Path>>(path: P) -> io::Result<UnixStream> {
+        UnixStream::connect_addr(&UnixAddr::Path(path.as_ref().to_path_buf()))
+    }
+
+    /// Connects to `addr`, which may be in the abstract namespace
+    pub fn connect_addr(addr: &UnixAddr) -> io::Result<UnixStream> {
+        let sock = try!(Socket::new(libc::SOCK_STREAM));
+        try!(sock.connect(addr));
+        Ok(UnixStream(sock))
+    }
+
+    /// Creates a pair of connected streams
+    pub fn pair() -> io::Result<(UnixStream, UnixStream)> {
+        let (a, b) = try!(Socket::pair(libc::SOCK_STREAM));
+        Ok((UnixStream(a), UnixStream(b)))
+    }
+
+    pub fn local_addr(&self) -> io::Result<UnixAddr> {
+        self.0.local_addr()
+    }
+
+    pub fn peer_addr(&self) -> io::Result<UnixAddr> {
+        self.0.peer_addr()
+    }
+
+    pub fn try_clone(&self) -> io::Result<UnixStream> {
+        Ok(UnixStream(try!(self.0.try_clone())))
+    }
+
+    /// Shuts down the read, write, or both halves of this connection
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.0.shutdown(how)
+    }
+
+    /// Sends `buf` together with the file descriptors `fds` (`SCM_RIGHTS`)
+    ///
+    /// The descriptors stay open in this process. `buf` must not be empty.
+    pub fn send_fds(&self, buf: &[u8], fds: &[RawFd]) -> io::Result<usize> {
+        self.0.send_msg(buf, fds, None)
+    }
+
+    /// Receives into `buf`, and the file descriptors sent with `send_fds` into `fds`
+    ///
+    /// Returns the number of bytes and the number of file descriptors received. The received
+    /// descriptors are owned by the caller, the ones which do not fit in `fds` are closed.
+    pub fn recv_fds(&self, buf: &mut [u8], fds: &mut [RawFd]) -> io::Result<(usize, usize)> {
+        let (len, nfds, _) = try!(self.0.recv_msg(buf, fds));
+        Ok((len, nfds))
+    }
+}
+
+impl io::Read for UnixStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.recv(buf)
+    }
+}
+
+impl io::Write for UnixStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.send(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Duplex for UnixStream {
+    fn try_clone(&self) -> io::Result<UnixStream> {
+        UnixStream::try_clone(self)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        UnixStream::shutdown(self, how)
+    }
+}
+
+impl AsRawFd for UnixStream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
+pub struct UnixDatagram(Socket);
+
+impl UnixDatagram {
+    /// Creates a datagram socket bound to the path `path`
+    pub fn bind<P: AsRef<Path>>(path: P) -> io::Result<UnixDatagram> {
+        UnixDatagram::bind_addr(&UnixAddr::Path(path.as_ref().to_path_buf()))
+    }
+
+    /// Creates a datagram socket bound to `addr`, which may be in the abstract namespace
+    pub fn bind_addr(addr: &UnixAddr) -> io::Result<UnixDatagram> {
+        let sock = try!(Socket::new(libc::SOCK_DGRAM));
+        try!(sock.bind(addr));
+        Ok(UnixDatagram(sock))
+    }
+
+    /// Creates a datagram socket which is not bound to any address
+    pub fn unbound() -> io::Result<UnixDatagram> {
+        Ok(UnixDatagram(try!(Socket::new(libc::SOCK_DGRAM))))
+    }
+
+    /// Creates a pair of connected datagram sockets
+    pub fn pair() -> io::Result<(UnixDatagram, UnixDatagram)> {
+        let (a, b) = try!(Socket::pair(libc::SOCK_DGRAM));
+        Ok((UnixDatagram(a), UnixDatagram(b)))
+    }
+
+    /// Connects this socket to the socket at `path`, so that `send` and `recv` can be used
+    pub fn connect<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        self.connect_addr(&UnixAddr::Path(path.as_ref().to_path_buf()))
+    }
+
+    /// Connects this socket to `addr`, which may be in the abstract namespace
+    pub fn connect_addr(&self, addr: &UnixAddr) -> io::Result<()> {
+        self.0.connect(addr)
+    }
+
+    pub fn local_addr(&self) -> io::Result<UnixAddr> {
+        self.0.local_addr()
+    }
+
+    pub fn peer_addr(&self) -> io::Result<UnixAddr> {
+        self.0.peer_addr()
+    }
+
+    pub fn try_clone(&self) -> io::Result<UnixDatagram> {
+        Ok(UnixDatagram(try!(self.0.try_clone())))
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.0.shutdown(how)
+    }
+
+    /// Sends a datagram to the socket at `path`, blocking the coroutine until the socket is writable
+    pub fn send_to<P: AsRef<Path>>(&self, buf: &[u8], path: P) -> io::Result<usize> {
+        self.send_to_addr(buf, &UnixAddr::Path(path.as_ref().to_path_buf()))
+    }
+
+    /// Sends a datagram to `addr`, blocking the coroutine until the socket is writable
+    pub fn send_to_addr(&self, buf: &[u8], addr: &UnixAddr) -> io::Result<usize> {
+        self.0.send_msg(buf, &[], Some(addr))
+    }
+
+    /// Receives a datagram, blocking the coroutine until one arrives
+    pub fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, UnixAddr)> {
+        let (len, _, addr) = try!(self.0.recv_msg(buf, &mut []));
+        Ok((len, addr))
+    }
+
+    /// Sends a datagram to the connected socket, blocking the coroutine until the socket is writable
+    pub fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        self.0.send(buf)
+    }
+
+    /// Receives a datagram from the connected socket, blocking the coroutine until one arrives
+    pub fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.recv(buf)
+    }
+
+    /// Sends a datagram together with the file descriptors `fds` (`SCM_RIGHTS`) to the connected socket
+    pub fn send_fds(&self, buf: &[u8], fds: &[RawFd]) -> io::Result<usize> {
+        self.0.send_msg(buf, fds, None)
+    }
+
+    /// Receives a datagram, and the file descriptors sent with it into `fds`
+    ///
+    /// Returns the length of the datagram, the number of file descriptors received and the source address.
+    /// The descriptors which do not fit in `fds` are closed.
+    pub fn recv_fds(&self, buf: &mut [u8], fds: &mut [RawFd]) -> io::Result<(usize, usize, UnixAddr)> {
+        self.0.recv_msg(buf, fds)
+    }
+}
+
+impl AsRawFd for UnixDatagram {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
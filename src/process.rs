@@ -0,0 +1,327 @@
+use std::io::{self, Read};
+use std::cmp;
+use std::ffi::OsStr;
+use std::path::Path;
+use std::process::{self, Stdio, ExitStatus, Output};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::{Arc, Mutex};
+
+use libc::{self, c_int, c_void, pid_t};
+#[cfg(any(target_os = "linux",
+          target_os = "android"))]
+use libc::c_long;
+
+use scheduler::Scheduler;
+use sync::Notify;
+use io::{Async, FileDesc, PipeReader, PipeWriter};
+use signal::{Signals, SIGCHLD};
+
+/// Upper bound of the polling interval when neither pidfd nor SIGCHLD can be waited for
+const MAX_POLL_DELAY_MS: u64 = 100;
+
+#[cfg(any(target_os = "linux",
+          target_os = "android"))]
+mod consts {
+    use libc::{c_int, c_long};
+
+    pub const SYS_PIDFD_OPEN: c_long = 434;
+
+    pub const P_PID: c_int = 1;
+    pub const WNOHANG: c_int = 0x00000001;
+    pub const WEXITED: c_int = 0x00000004;
+    pub const WNOWAIT: c_int = 0x01000000;
+}
+
+#[cfg(any(target_os = "macos",
+          target_os = "freebsd",
+          target_os = "dragonfly",
+          target_os = "ios",
+          target_os = "bitrig",
+          target_os = "openbsd"))]
+mod consts {
+    use libc::c_int;
+
+    pub const P_PID: c_int = 0;
+    pub const WNOHANG: c_int = 0x00000001;
+    pub const WEXITED: c_int = 0x00000004;
+    pub const WNOWAIT: c_int = 0x00000020;
+}
+
+use self::consts::*;
+
+extern {
+    fn waitid(idtype: c_int, id: u32, infop: *mut c_void, options: c_int) -> c_int;
+}
+
+#[cfg(any(target_os = "linux",
+          target_os = "android"))]
+extern {
+    fn syscall(num: c_long, ...) -> c_long;
+}
+
+/// A `std::process::Command` whose children can be waited for without blocking the scheduler
+///
+/// Piped standard streams of the child are non-blocking and park the current coroutine.
+pub struct Command {
+    inner: process::Command,
+    kill_on_drop: bool,
+}
+
+impl Command {
+    pub fn new<S: AsRef<OsStr>>(program: S) -> Command {
+        Command {
+            inner: process::Command::new(program),
+            kill_on_drop: false,
+        }
+    }
+
+    pub fn arg<S: AsRef<OsStr>>(&mut self, arg: S) -> &mut Command {
+        self.inner.arg(arg);
+        self
+    }
+
+    pub fn args<S: AsRef<OsStr>>(&mut self, args: &[S]) -> &mut Command {
+        self.inner.args(args);
+        self
+    }
+
+    pub fn env<K: AsRef<OsStr>, V: AsRef<OsStr>>(&mut self, key: K, val: V) -> &mut Command {
+        self.inner.env(key, val);
+        self
+    }
+
+    pub fn current_dir<P: AsRef<Path>>(&mut self, dir: P) -> &mut Command {
+        self.inner.current_dir(dir);
+        self
+    }
+
+    pub fn stdin(&mut self, cfg: Stdio) -> &mut Command {
+        self.inner.stdin(cfg);
+        self
+    }
+
+    pub fn stdout(&mut self, cfg: Stdio) -> &mut Command {
+        self.inner.stdout(cfg);
+        self
+    }
+
+    pub fn stderr(&mut self, cfg: Stdio) -> &mut Command {
+        self.inner.stderr(cfg);
+        self
+    }
+
+    /// Kills and reaps the child when its `Child` is dropped before it was waited for
+    pub fn kill_on_drop(&mut self, kill: bool) -> &mut Command {
+        self.kill_on_drop = kill;
+        self
+    }
+
+    pub fn spawn(&mut self) -> io::Result<Child> {
+        let mut child = try!(self.inner.spawn());
+
+        // Keep our own copies of the pipes, the std handles are closed with `child.stdin` and so on
+        let stdin = match child.stdin.take() {
+            Some(s) => Some(try!(PipeWriter::from_fd(try!(FileDesc::dup(s.as_raw_fd()))))),
+            None => None,
+        };
+        let stdout = match child.stdout.take() {
+            Some(s) => Some(try!(PipeReader::from_fd(try!(FileDesc::dup(s.as_raw_fd()))))),
+            None => None,
+        };
+        let stderr = match child.stderr.take() {
+            Some(s) => Some(try!(PipeReader::from_fd(try!(FileDesc::dup(s.as_raw_fd()))))),
+            None => None,
+        };
+
+        Ok(Child {
+            inner: child,
+            stdin: stdin,
+            stdout: stdout,
+            stderr: stderr,
+            kill_on_drop: self.kill_on_drop,
+            waited: false,
+        })
+    }
+
+    /// Runs the child and waits for it to exit
+    pub fn status(&mut self) -> io::Result<ExitStatus> {
+        try!(self.spawn()).wait()
+    }
+
+    /// Runs the child with piped stdout and stderr, and collects them
+    ///
+    /// The stderr is read by another coroutine, so a child filling one of the pipes cannot deadlock.
+    pub fn output(&mut self) -> io::Result<Output> {
+        self.inner.stdout(Stdio::piped());
+        self.inner.stderr(Stdio::piped());
+        let mut child = try!(self.spawn());
+        drop(child.stdin.take());
+
+        let stderr_result = Arc::new(Mutex::new(None));
+        let done = Notify::new();
+        if let Some(mut stderr) = child.stderr.take() {
+            let stderr_result = stderr_result.clone();
+            let done = done.clone();
+            Scheduler::spawn(move|| {
+                let mut buf = Vec::new();
+                let result = stderr.read_to_end(&mut buf).map(|_| buf);
+                *stderr_result.lock().unwrap() = Some(result);
+                done.notify();
+            });
+        } else {
+            done.notify();
+        }
+
+        let mut stdout = Vec::new();
+        let stdout_result = match child.stdout.take() {
+            Some(mut s) => s.read_to_end(&mut stdout).map(|_| ()),
+            None => Ok(()),
+        };
+
+        done.wait();
+        let stderr = match stderr_result.lock().unwrap().take() {
+            Some(result) => try!(result),
+            None => Vec::new(),
+        };
+        try!(stdout_result);
+
+        let status = try!(child.wait());
+        Ok(Output {
+            status: status,
+            stdout: stdout,
+            stderr: stderr,
+        })
+    }
+}
+
+/// A child process spawned by `Command`
+pub struct Child {
+    inner: process::Child,
+    pub stdin: Option<PipeWriter>,
+    pub stdout: Option<PipeReader>,
+    pub stderr: Option<PipeReader>,
+    kill_on_drop: bool,
+    waited: bool,
+}
+
+impl Child {
+    pub fn id(&self) -> u32 {
+        self.inner.id()
+    }
+
+    /// Sends SIGKILL to the child
+    pub fn kill(&mut self) -> io::Result<()> {
+        self.inner.kill()
+    }
+
+    /// Waits for the child to exit, parking only the current coroutine
+    ///
+    /// The stdin of the child is closed first, so that it does not wait for more input. Uses a
+    /// pidfd registered in the event loop where available, and waits for SIGCHLD otherwise. The
+    /// previous SIGCHLD disposition is replaced during that wait and restored afterwards.
+    pub fn wait(&mut self) -> io::Result<ExitStatus> {
+        drop(self.stdin.take());
+
+        if !self.waited {
+            try!(wait_exit(self.inner.id() as pid_t));
+        }
+
+        // Does not block, the child has exited
+        let status = try!(self.inner.wait());
+        self.waited = true;
+        Ok(status)
+    }
+}
+
+impl Drop for Child {
+    fn drop(&mut self) {
+        if self.kill_on_drop && !self.waited {
+            debug!("Killing child {} on drop", self.inner.id());
+            if self.inner.kill().is_ok() {
+                // Reaped in the background, the child may take a while to die
+                let pid = self.inner.id() as pid_t;
+                Scheduler::spawn(move|| {
+                    match wait_exit(pid) {
+                        Ok(..) => unsafe {
+                            let mut status = 0;
+                            libc::waitpid(pid, &mut status, 0);
+                        },
+                        Err(err) => error!("Failed to reap killed child {}: {:?}", pid, err),
+                    }
+                });
+            }
+        }
+    }
+}
+
+fn wait_exit(pid: pid_t) -> io::Result<()> {
+    match pidfd_open(pid) {
+        Ok(pidfd) => {
+            // A pidfd becomes readable when the process exits
+            let pidfd = try!(Async::new(pidfd));
+            return pidfd.read_with(|_| {
+                if try!(has_exited(pid)) {
+                    Ok(())
+                } else {
+                    Err(io::Error::new(io::ErrorKind::WouldBlock, "child is still running"))
+                }
+            });
+        },
+        Err(err) => {
+            debug!("pidfd_open({}) failed: {:?}; going to wait for SIGCHLD", pid, err);
+        }
+    }
+
+    // Registered before the first check, so an exit in between still wakes us up
+    match Signals::new(&[SIGCHLD]) {
+        Ok(mut signals) => {
+            while !try!(has_exited(pid)) {
+                try!(signals.recv());
+            }
+            return Ok(());
+        },
+        Err(err) => {
+            debug!("Cannot catch SIGCHLD for {}: {:?}; going to poll", pid, err);
+        }
+    }
+
+    let mut delay = 1;
+    while !try!(has_exited(pid)) {
+        try!(Scheduler::current().sleep_ms(delay));
+        delay = cmp::min(delay * 2, MAX_POLL_DELAY_MS);
+    }
+    Ok(())
+}
+
+// Checks whether the child has exited, and leaves it waitable
+fn has_exited(pid: pid_t) -> io::Result<bool> {
+    // siginfo_t is 128 bytes. si_signo, its first field, stays 0 if the child is running.
+    let mut info = [0u64; 16];
+    let ret = unsafe {
+        waitid(P_PID, pid as u32, info.as_mut_ptr() as *mut c_void, WEXITED | WNOHANG | WNOWAIT)
+    };
+
+    if ret == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { *(info.as_ptr() as *const c_int) } != 0)
+}
+
+#[cfg(any(target_os = "linux",
+          target_os = "android"))]
+fn pidfd_open(pid: pid_t) -> io::Result<FileDesc> {
+    let fd = unsafe { syscall(SYS_PIDFD_OPEN, pid as c_long, 0 as c_long) };
+    if fd == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let fd = FileDesc::new(fd as RawFd);
+    try!(fd.set_cloexec());
+    Ok(fd)
+}
+
+#[cfg(not(any(target_os = "linux",
+              target_os = "android")))]
+fn pidfd_open(_: pid_t) -> io::Result<FileDesc> {
+    Err(io::Error::new(io::ErrorKind::Other, "pidfd is only available on Linux"))
+}
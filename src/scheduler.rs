@@ -6,7 +6,6 @@ use std::sync::{Mutex, Once, ONCE_INIT};
 use std::mem;
 use std::cell::UnsafeCell;
 use std::io;
-#[cfg(target_os = "linux")]
 use std::os::unix::io::AsRawFd;
 #[cfg(target_os = "linux")]
 use std::convert::From;
@@ -19,9 +18,8 @@ use coroutine::coroutine::{State, Handle, Coroutine};
 
 use deque::{BufferPool, Stealer, Worker, Stolen};
 
-use mio::{EventLoop, Evented, Handler, Token, ReadHint, Interest, PollOpt};
+use mio::{EventLoop, Evented, Handler, Token, ReadHint, Interest, PollOpt, Timeout};
 use mio::util::Slab;
-#[cfg(target_os = "linux")]
 use mio::Io;
 
 static mut THREAD_HANDLES: *const Mutex<Vec<(Sender<SchedMessage>, Stealer<Handle>)>> =
@@ -46,11 +44,33 @@ thread_local!(static SCHEDULER: UnsafeCell<Scheduler> = UnsafeCell::new(Schedule
 
 pub enum SchedMessage {
     NewNeighbor(Sender<SchedMessage>, Stealer<Handle>),
+    Pinned(Handle),
     Shutdown,
 }
 
 const MAX_PRIVATE_WORK_NUM: usize = 10;
 
+/// Settings for `Scheduler::run_with_config`
+#[derive(Clone, Debug)]
+pub struct SchedulerConfig {
+    threads: usize,
+}
+
+impl SchedulerConfig {
+    /// One scheduler thread
+    pub fn new() -> SchedulerConfig {
+        SchedulerConfig {
+            threads: 1,
+        }
+    }
+
+    /// Sets the number of scheduler threads, must be at least 1
+    pub fn threads(mut self, threads: usize) -> SchedulerConfig {
+        self.threads = threads;
+        self
+    }
+}
+
 pub struct Scheduler {
     workqueue: Worker<Handle>,
     workstealer: Stealer<Handle>,
@@ -63,6 +83,13 @@ pub struct Scheduler {
     handler: SchedulerHandler,
 
     private_work: VecDeque<Handle>,
+
+    // Coroutines that will never be stolen by the neighbors
+    pinned_work: VecDeque<Handle>,
+    running_pinned: bool,
+
+    // Called with the running coroutine once it has blocked, see `block_with`
+    block_hook: Option<Box<FnMut(Handle) -> Option<Handle>>>,
 }
 
 impl Scheduler {
@@ -95,6 +122,11 @@ impl Scheduler {
             handler: SchedulerHandler::new(),
 
             private_work: VecDeque::new(),
+
+            pinned_work: VecDeque::new(),
+            running_pinned: false,
+
+            block_hook: None,
         }
     }
 
@@ -115,6 +147,24 @@ impl Scheduler {
         Coroutine::sched();
     }
 
+    /// Spawns a coroutine which always runs on the `idx`-th scheduler thread (modulo the number of threads)
+    ///
+    /// It is never stolen by the other schedulers while it is yielding or waiting for I/O events.
+    pub fn spawn_pinned<F>(idx: usize, f: F)
+            where F: FnOnce() + Send + 'static {
+
+        let coro = spawn(f);
+
+        let guard = schedulers().lock().unwrap();
+        let &(ref chan, _) = &guard[idx % guard.len()];
+        chan.send(SchedMessage::Pinned(coro)).unwrap();
+    }
+
+    /// Number of scheduler threads
+    pub fn threads() -> usize {
+        schedulers().lock().unwrap().len()
+    }
+
     pub fn ready(&mut self, work: Handle) {
         if self.private_work.len() >= MAX_PRIVATE_WORK_NUM {
             self.workqueue.push(work);
@@ -123,6 +173,32 @@ impl Scheduler {
         }
     }
 
+    /// Blocks the current coroutine and calls `f` with its handle once it is really blocked
+    ///
+    /// `f` may hand the handle to whoever wakes the coroutine up, so the waker never readies a
+    /// coroutine which is still running. If `f` returns the handle, the coroutine is resumed at once.
+    pub fn block_with<F>(&mut self, f: F)
+            where F: FnOnce(Handle) -> Option<Handle> + 'static {
+        let mut f = Some(f);
+        self.block_hook = Some(Box::new(move|hdl| {
+            match f.take() {
+                Some(f) => f(hdl),
+                None => None,
+            }
+        }));
+        Coroutine::block();
+    }
+
+    fn ready_pinned(&mut self, work: Handle) {
+        self.pinned_work.push_back(work);
+    }
+
+    /// Runs `f` in a coroutine with the schedulers described by `config`
+    pub fn run_with_config<F>(f: F, config: &SchedulerConfig)
+            where F: FnOnce() + Send + 'static {
+        Scheduler::run(f, config.threads)
+    }
+
     pub fn run<F>(f: F, threads: usize)
             where F: FnOnce() + Send + 'static {
 
@@ -171,12 +247,16 @@ impl Scheduler {
         SCHEDULER_HAS_STARTED.store(false, Ordering::SeqCst);
     }
 
-    fn resume_coroutine(&mut self, work: Handle) {
+    fn resume_coroutine(&mut self, work: Handle, pinned: bool) {
         match work.state() {
             State::Suspended | State::Blocked => {
                 debug!("Resuming Coroutine: {:?}", work);
 
-                if let Err(err) = work.resume() {
+                self.running_pinned = pinned;
+                let result = work.resume();
+                self.running_pinned = false;
+
+                if let Err(err) = result {
                     let msg = match err.downcast_ref::<&'static str>() {
                         Some(s) => *s,
                         None => match err.downcast_ref::<String>() {
@@ -194,10 +274,19 @@ impl Scheduler {
                     },
                     State::Suspended => {
                         debug!("Coroutine suspended, going to be resumed next round");
-                        self.ready(work);
+                        if pinned {
+                            self.ready_pinned(work);
+                        } else {
+                            self.ready(work);
+                        }
                     },
                     State::Blocked => {
                         debug!("Coroutine blocked, maybe waiting for I/O");
+                        if let Some(mut hook) = self.block_hook.take() {
+                            if let Some(work) = hook(work) {
+                                self.wake(work, pinned);
+                            }
+                        }
                     },
                     State::Finished | State::Panicked => {
                         debug!("Coroutine state: {:?}, will not be resumed automatically", work.state());
@@ -217,6 +306,9 @@ impl Scheduler {
                 Ok(SchedMessage::NewNeighbor(tx, st)) => {
                     self.neighbors.push((tx, st));
                 },
+                Ok(SchedMessage::Pinned(work)) => {
+                    self.ready_pinned(work);
+                },
                 Ok(SchedMessage::Shutdown) => {
                     info!("Shutting down");
                     break;
@@ -238,15 +330,23 @@ impl Scheduler {
             //     self.resume_coroutine(work);
             // }
 
+            // Only the ones queued before this round, a yielding pinned coroutine is queued again
+            for _ in 0..self.pinned_work.len() {
+                if let Some(work) = self.pinned_work.pop_front() {
+                    need_steal = false;
+                    self.resume_coroutine(work, true);
+                }
+            }
+
             while let Some(work) = self.private_work.pop_front() {
                 need_steal = false;
-                self.resume_coroutine(work);
+                self.resume_coroutine(work, false);
             }
 
             if need_steal {
                 if let Stolen::Data(work) = self.workstealer.steal() {
                     need_steal = false;
-                    self.resume_coroutine(work);
+                    self.resume_coroutine(work, false);
                 }
             }
 
@@ -279,7 +379,7 @@ impl Scheduler {
                     .collect::<Vec<Handle>>();
             for work in stolen_works.into_iter() {
                 has_stolen = true;
-                self.resume_coroutine(work);
+                self.resume_coroutine(work, false);
             }
 
             if !has_stolen {
@@ -291,6 +391,82 @@ impl Scheduler {
     // fn resume(&mut self, handle: Handle) {
     //     self.workqueue.push(handle);
     // }
+
+impl Scheduler {
+    /// Suspends the current coroutine for at least `ms` milliseconds without blocking the thread
+    ///
+    /// Fails without waiting if too many coroutines are waiting already, or the timer is full.
+    pub fn sleep_ms(&mut self, ms: u64) -> io::Result<()> {
+        let timed_out = Arc::new(AtomicBool::new(false));
+        let waiter = Waiter::timer(Coroutine::current(), self.running_pinned, timed_out);
+        let token = match self.handler.slabs.insert(waiter) {
+            Ok(token) => token,
+            Err(..) => return Err(io::Error::new(io::ErrorKind::Other, "Too many waiting coroutines")),
+        };
+        if let Err(err) = self.arm_timeout(token, Some(ms)) {
+            self.discard_waiter(token);
+            return Err(err);
+        }
+
+        debug!("sleep_ms: Blocked current Coroutine ...; token={:?}", token);
+        Coroutine::block();
+        debug!("sleep_ms: Waked up; token={:?}", token);
+        Ok(())
+    }
+
+    fn wake(&mut self, work: Handle, pinned: bool) {
+        if pinned {
+            self.ready_pinned(work);
+        } else {
+            self.ready(work);
+        }
+    }
+
+    fn arm_timeout(&mut self, token: Token, timeout_ms: Option<u64>) -> io::Result<()> {
+        if let Some(ms) = timeout_ms {
+            let timeout = try!(self.eventloop.timeout_ms(token, ms).map_err(|err| {
+                io::Error::new(io::ErrorKind::Other, format!("Cannot set timeout: {:?}", err))
+            }));
+            self.handler.slabs[token].timeout = Some(timeout);
+        }
+        Ok(())
+    }
+
+    /// Removes the waiter of a coroutine which fails instead of blocking
+    fn discard_waiter(&mut self, token: Token) {
+        if let Some(waiter) = self.handler.slabs.remove(token) {
+            if let Some(timeout) = waiter.timeout {
+                self.eventloop.clear_timeout(timeout);
+            }
+            waiter.discard();
+        }
+    }
+}
+
+/// Calls `f` again after waiting for `interest` on `io` as long as it fails with `WouldBlock`
+pub fn retry_nonblocking<E, F, T>(io: &E, interest: Interest, mut f: F) -> io::Result<T>
+    where E: Evented + AsRawFd,
+          F: FnMut() -> io::Result<T>
+{
+    loop {
+        match f() {
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                debug!("retry_nonblocking WouldBlock; going to register event");
+            },
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            result => return result,
+        }
+
+        try!(Scheduler::current().wait_event(io, interest));
+    }
+}
+
+fn check_timed_out(timed_out: &AtomicBool) -> io::Result<()> {
+    if timed_out.load(Ordering::SeqCst) {
+        Err(io::Error::new(io::ErrorKind::TimedOut, "wait_event timed out"))
+    } else {
+        Ok(())
+    }
 }
 
 const MAX_TOKEN_NUM: usize = 102400;
@@ -301,50 +477,37 @@ impl SchedulerHandler {
             slabs: Slab::new(MAX_TOKEN_NUM),
         }
     }
-}
 
-#[cfg(any(target_os = "linux",
-          target_os = "android"))]
-impl Scheduler {
-    pub fn wait_event<E: Evented + AsRawFd>(&mut self, fd: &E, interest: Interest) -> io::Result<()> {
-        let token = self.handler.slabs.insert((Coroutine::current(), From::from(fd.as_raw_fd()))).unwrap();
-        try!(self.eventloop.register_opt(fd, token, interest,
-                                         PollOpt::level()|PollOpt::oneshot()));
-
-        debug!("wait_event: Blocked current Coroutine ...; token={:?}", token);
-        Coroutine::block();
-        debug!("wait_event: Waked up; token={:?}", token);
-
-        Ok(())
+    fn wakeup(&mut self, event_loop: &mut EventLoop<SchedulerHandler>, token: Token) -> bool {
+        match self.slabs.remove(token) {
+            Some(waiter) => {
+                if let Some(timeout) = waiter.timeout {
+                    event_loop.clear_timeout(timeout);
+                }
+                let pinned = waiter.pinned;
+                let hdl = waiter.release(event_loop);
+                Scheduler::current().wake(hdl, pinned);
+                true
+            },
+            None => false
+        }
     }
 }
 
-#[cfg(any(target_os = "linux",
-          target_os = "android"))]
 struct SchedulerHandler {
-    slabs: Slab<(Handle, Io)>,
+    slabs: Slab<Waiter>,
 }
 
-#[cfg(any(target_os = "linux",
-          target_os = "android"))]
 impl Handler for SchedulerHandler {
-    type Timeout = ();
+    type Timeout = Token;
     type Message = ();
 
     fn writable(&mut self, event_loop: &mut EventLoop<Self>, token: Token) {
 
         debug!("In writable, token {:?}", token);
 
-        match self.slabs.remove(token) {
-            Some((hdl, fd)) => {
-                // Linux EPoll needs to explicit EPOLL_CTL_DEL the fd
-                event_loop.deregister(&fd).unwrap();
-                mem::forget(fd);
-                Scheduler::current().ready(hdl);
-            },
-            None => {
-                warn!("No coroutine is waiting on writable {:?}", token);
-            }
+        if !self.wakeup(event_loop, token) {
+            warn!("No coroutine is waiting on writable {:?}", token);
         }
 
     }
@@ -353,18 +516,121 @@ impl Handler for SchedulerHandler {
 
         debug!("In readable, token {:?}, hint {:?}", token, hint);
 
+        if !self.wakeup(event_loop, token) {
+            warn!("No coroutine is waiting on readable {:?}", token);
+        }
+
+    }
+
+    fn timeout(&mut self, event_loop: &mut EventLoop<Self>, token: Token) {
+
+        debug!("In timeout, token {:?}", token);
+
         match self.slabs.remove(token) {
-            Some((hdl, fd)) => {
-                // Linux EPoll needs to explicit EPOLL_CTL_DEL the fd
-                event_loop.deregister(&fd).unwrap();
-                mem::forget(fd);
-                Scheduler::current().ready(hdl);
+            Some(waiter) => {
+                waiter.timed_out.store(true, Ordering::SeqCst);
+                let pinned = waiter.pinned;
+                let hdl = waiter.release(event_loop);
+                Scheduler::current().wake(hdl, pinned);
             },
             None => {
-                warn!("No coroutine is waiting on readable {:?}", token);
+                warn!("No coroutine is waiting on timeout {:?}", token);
+            }
+        }
+
+    }
+}
+
+#[cfg(any(target_os = "linux",
+          target_os = "android"))]
+impl Scheduler {
+    pub fn wait_event<E: Evented + AsRawFd>(&mut self, fd: &E, interest: Interest) -> io::Result<()> {
+        self.wait_event_opt(fd, interest, None)
+    }
+
+    /// Same as `wait_event`, but fails with `TimedOut` if the event does not arrive within `timeout_ms`
+    pub fn wait_event_timeout<E: Evented + AsRawFd>(&mut self, fd: &E, interest: Interest, timeout_ms: u64)
+            -> io::Result<()> {
+        self.wait_event_opt(fd, interest, Some(timeout_ms))
+    }
+
+    fn wait_event_opt<E: Evented + AsRawFd>(&mut self, fd: &E, interest: Interest, timeout_ms: Option<u64>)
+            -> io::Result<()> {
+        let timed_out = Arc::new(AtomicBool::new(false));
+        let waiter = Waiter::io(Coroutine::current(), From::from(fd.as_raw_fd()), self.running_pinned,
+                                timed_out.clone());
+        let token = match self.handler.slabs.insert(waiter) {
+            Ok(token) => token,
+            Err(waiter) => {
+                mem::forget(waiter.io);
+                return Err(io::Error::new(io::ErrorKind::Other, "Too many waiting coroutines"));
             }
+        };
+        if let Err(err) = self.arm_timeout(token, timeout_ms) {
+            self.discard_waiter(token);
+            return Err(err);
+        }
+        if let Err(err) = self.eventloop.register_opt(fd, token, interest,
+                                                      PollOpt::level()|PollOpt::oneshot()) {
+            self.discard_waiter(token);
+            return Err(err);
         }
 
+        debug!("wait_event: Blocked current Coroutine ...; token={:?}", token);
+        Coroutine::block();
+        debug!("wait_event: Waked up; token={:?}", token);
+
+        check_timed_out(&timed_out)
+    }
+}
+
+#[cfg(any(target_os = "linux",
+          target_os = "android"))]
+struct Waiter {
+    coro: Handle,
+    io: Option<Io>,
+    pinned: bool,
+    timeout: Option<Timeout>,
+    timed_out: Arc<AtomicBool>,
+}
+
+#[cfg(any(target_os = "linux",
+          target_os = "android"))]
+impl Waiter {
+    fn io(coro: Handle, io: Io, pinned: bool, timed_out: Arc<AtomicBool>) -> Waiter {
+        Waiter {
+            coro: coro,
+            io: Some(io),
+            pinned: pinned,
+            timeout: None,
+            timed_out: timed_out,
+        }
+    }
+
+    fn timer(coro: Handle, pinned: bool, timed_out: Arc<AtomicBool>) -> Waiter {
+        Waiter {
+            coro: coro,
+            io: None,
+            pinned: pinned,
+            timeout: None,
+            timed_out: timed_out,
+        }
+    }
+
+    fn release(self, event_loop: &mut EventLoop<SchedulerHandler>) -> Handle {
+        if let Some(fd) = self.io {
+            // Linux EPoll needs to explicit EPOLL_CTL_DEL the fd
+            event_loop.deregister(&fd).unwrap();
+            mem::forget(fd);
+        }
+        self.coro
+    }
+
+    /// Drops a waiter whose fd was never registered, without closing the fd
+    fn discard(self) {
+        if let Some(fd) = self.io {
+            mem::forget(fd);
+        }
     }
 }
 
@@ -375,16 +641,43 @@ impl Handler for SchedulerHandler {
           target_os = "bitrig",
           target_os = "openbsd"))]
 impl Scheduler {
-    pub fn wait_event<E: Evented>(&mut self, fd: &E, interest: Interest) -> io::Result<()> {
-        let token = self.handler.slabs.insert(Coroutine::current()).unwrap();
-        try!(self.eventloop.register_opt(fd, token, interest,
-                                         PollOpt::level()|PollOpt::oneshot()));
+    pub fn wait_event<E: Evented + AsRawFd>(&mut self, fd: &E, interest: Interest) -> io::Result<()> {
+        self.wait_event_opt(fd, interest, None)
+    }
+
+    /// Same as `wait_event`, but fails with `TimedOut` if the event does not arrive within `timeout_ms`
+    pub fn wait_event_timeout<E: Evented + AsRawFd>(&mut self, fd: &E, interest: Interest, timeout_ms: u64)
+            -> io::Result<()> {
+        self.wait_event_opt(fd, interest, Some(timeout_ms))
+    }
+
+    fn wait_event_opt<E: Evented + AsRawFd>(&mut self, fd: &E, interest: Interest, timeout_ms: Option<u64>)
+            -> io::Result<()> {
+        let timed_out = Arc::new(AtomicBool::new(false));
+        let waiter = Waiter::io(Coroutine::current(), From::from(fd.as_raw_fd()), self.running_pinned,
+                                timed_out.clone());
+        let token = match self.handler.slabs.insert(waiter) {
+            Ok(token) => token,
+            Err(waiter) => {
+                mem::forget(waiter.io);
+                return Err(io::Error::new(io::ErrorKind::Other, "Too many waiting coroutines"));
+            }
+        };
+        if let Err(err) = self.arm_timeout(token, timeout_ms) {
+            self.discard_waiter(token);
+            return Err(err);
+        }
+        if let Err(err) = self.eventloop.register_opt(fd, token, interest,
+                                                      PollOpt::level()|PollOpt::oneshot()) {
+            self.discard_waiter(token);
+            return Err(err);
+        }
 
         debug!("wait_event: Blocked current Coroutine ...; token={:?}", token);
         Coroutine::block();
         debug!("wait_event: Waked up; token={:?}", token);
 
-        Ok(())
+        check_timed_out(&timed_out)
     }
 }
 
@@ -394,8 +687,12 @@ impl Scheduler {
           target_os = "ios",
           target_os = "bitrig",
           target_os = "openbsd"))]
-struct SchedulerHandler {
-    slabs: Slab<Handle>,
+struct Waiter {
+    coro: Handle,
+    io: Option<Io>,
+    pinned: bool,
+    timeout: Option<Timeout>,
+    timed_out: Arc<AtomicBool>,
 }
 
 #[cfg(any(target_os = "macos",
@@ -404,37 +701,45 @@ struct SchedulerHandler {
           target_os = "ios",
           target_os = "bitrig",
           target_os = "openbsd"))]
-impl Handler for SchedulerHandler {
-    type Timeout = ();
-    type Message = ();
-
-    fn writable(&mut self, _: &mut EventLoop<Self>, token: Token) {
-
-        debug!("In writable, token {:?}", token);
-
-        match self.slabs.remove(token) {
-            Some(hdl) => {
-                Scheduler::current().ready(hdl);
-            },
-            None => {
-                warn!("No coroutine is waiting on writable {:?}", token);
-            }
+impl Waiter {
+    fn io(coro: Handle, io: Io, pinned: bool, timed_out: Arc<AtomicBool>) -> Waiter {
+        Waiter {
+            coro: coro,
+            io: Some(io),
+            pinned: pinned,
+            timeout: None,
+            timed_out: timed_out,
         }
-
     }
 
-    fn readable(&mut self, _: &mut EventLoop<Self>, token: Token, hint: ReadHint) {
-
-        debug!("In readable, token {:?}, hint {:?}", token, hint);
+    fn timer(coro: Handle, pinned: bool, timed_out: Arc<AtomicBool>) -> Waiter {
+        Waiter {
+            coro: coro,
+            io: None,
+            pinned: pinned,
+            timeout: None,
+            timed_out: timed_out,
+        }
+    }
 
-        match self.slabs.remove(token) {
-            Some(hdl) => {
-                Scheduler::current().ready(hdl);
-            },
-            None => {
-                warn!("No coroutine is waiting on readable {:?}", token);
+    fn release(self, event_loop: &mut EventLoop<SchedulerHandler>) -> Handle {
+        if let Some(fd) = self.io {
+            // A oneshot kevent is deleted once it fired, but a timed out one stays armed on the
+            // token, and would wake up the next coroutine which gets it
+            if self.timed_out.load(Ordering::SeqCst) {
+                if let Err(err) = event_loop.deregister(&fd) {
+                    debug!("Cannot deregister timed out fd: {:?}", err);
+                }
             }
+            mem::forget(fd);
         }
+        self.coro
+    }
 
+    /// Drops a waiter whose fd was never registered, without closing the fd
+    fn discard(self) {
+        if let Some(fd) = self.io {
+            mem::forget(fd);
+        }
     }
 }
@@ -0,0 +1,326 @@
+//! Unix signals delivered as coroutine events
+//!
+//! A signal handler writes the number of every caught signal into the non-blocking pipe of each
+//! `Signals` registered for it. `Signals::recv` reads that pipe and parks the coroutine on the
+//! event loop until a signal arrives.
+
+use std::io::{self, Read};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::mem;
+use std::sync::{StaticMutex, MUTEX_INIT};
+use std::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
+use std::thread;
+
+use libc::{self, c_int, c_void, size_t};
+
+use io::{pipe, PipeReader, PipeWriter};
+
+pub const SIGHUP: c_int = 1;
+pub const SIGINT: c_int = 2;
+pub const SIGQUIT: c_int = 3;
+pub const SIGTERM: c_int = 15;
+
+#[cfg(any(target_os = "linux",
+          target_os = "android"))]
+mod consts {
+    use libc::c_int;
+
+    pub const SIGUSR1: c_int = 10;
+    pub const SIGUSR2: c_int = 12;
+    pub const SIGCHLD: c_int = 17;
+}
+
+#[cfg(any(target_os = "macos",
+          target_os = "freebsd",
+          target_os = "dragonfly",
+          target_os = "ios",
+          target_os = "bitrig",
+          target_os = "openbsd"))]
+mod consts {
+    use libc::c_int;
+
+    pub const SIGUSR1: c_int = 30;
+    pub const SIGUSR2: c_int = 31;
+    pub const SIGCHLD: c_int = 20;
+}
+
+pub use self::consts::*;
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use libc::c_int;
+
+    pub const SA_RESTART: c_int = 0x10000000;
+
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    pub struct sigaction {
+        pub sa_sigaction: usize,
+        pub sa_mask: [u64; 16],
+        pub sa_flags: c_int,
+        pub sa_restorer: usize,
+    }
+}
+
+#[cfg(target_os = "android")]
+mod sys {
+    use libc::{c_int, c_ulong};
+
+    pub const SA_RESTART: c_int = 0x10000000;
+
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    pub struct sigaction {
+        pub sa_sigaction: usize,
+        pub sa_mask: c_ulong,
+        pub sa_flags: c_int,
+        pub sa_restorer: usize,
+    }
+}
+
+#[cfg(any(target_os = "macos",
+          target_os = "ios",
+          target_os = "bitrig",
+          target_os = "openbsd"))]
+mod sys {
+    use libc::c_int;
+
+    pub const SA_RESTART: c_int = 0x0002;
+
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    pub struct sigaction {
+        pub sa_sigaction: usize,
+        pub sa_mask: u32,
+        pub sa_flags: c_int,
+    }
+}
+
+#[cfg(any(target_os = "freebsd",
+          target_os = "dragonfly"))]
+mod sys {
+    use libc::c_int;
+
+    pub const SA_RESTART: c_int = 0x0002;
+
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    pub struct sigaction {
+        pub sa_sigaction: usize,
+        pub sa_flags: c_int,
+        pub sa_mask: [u32; 4],
+    }
+}
+
+/// Signals are numbered below this
+const NSIG: usize = 32;
+
+/// Maximum number of `Signals` registered for one signal at the same time
+const MAX_RECEIVERS: usize = 16;
+
+// Write ends of the pipes registered for each signal, -1 marks a free slot. Modified under
+// `REGISTRY_LOCK`, read by the signal handler without locking.
+static mut RECEIVERS: [[RawFd; MAX_RECEIVERS]; NSIG] = [[-1; MAX_RECEIVERS]; NSIG];
+// Disposition of each signal before `handler` was installed for it, restored once no `Signals`
+// catches it anymore. `None` while `handler` is not installed.
+static mut PREVIOUS_ACTIONS: [Option<sys::sigaction>; NSIG] = [None; NSIG];
+static REGISTRY_LOCK: StaticMutex = MUTEX_INIT;
+
+// Signal handlers currently running on any thread. A write end is only closed once this dropped
+// to 0 after it was unregistered, so no handler can write to a reused descriptor.
+static HANDLERS_RUNNING: AtomicUsize = ATOMIC_USIZE_INIT;
+
+extern {
+    fn sigaction(signum: c_int, act: *const sys::sigaction, oldact: *mut sys::sigaction) -> c_int;
+}
+
+#[cfg(any(target_os = "linux",
+          target_os = "android"))]
+extern {
+    #[link_name = "__errno_location"]
+    fn errno_location() -> *mut c_int;
+}
+
+#[cfg(any(target_os = "macos",
+          target_os = "freebsd",
+          target_os = "dragonfly",
+          target_os = "ios"))]
+extern {
+    #[link_name = "__error"]
+    fn errno_location() -> *mut c_int;
+}
+
+#[cfg(any(target_os = "bitrig",
+          target_os = "openbsd"))]
+extern {
+    #[link_name = "__errno"]
+    fn errno_location() -> *mut c_int;
+}
+
+extern "C" fn handler(signum: c_int) {
+    // Only async-signal-safe calls in here, and errno must survive for the interrupted code
+    HANDLERS_RUNNING.fetch_add(1, Ordering::SeqCst);
+    unsafe {
+        let saved_errno = *errno_location();
+
+        let byte = signum as u8;
+        for &fd in RECEIVERS[signum as usize].iter() {
+            if fd != -1 {
+                // A full pipe already holds pending signals for the receiver, so losing this one is fine
+                libc::write(fd, &byte as *const u8 as *const c_void, 1 as size_t);
+            }
+        }
+
+        *errno_location() = saved_errno;
+    }
+    HANDLERS_RUNNING.fetch_sub(1, Ordering::SeqCst);
+}
+
+/// A stream of Unix signals which can be received by a coroutine
+///
+/// While a signal is registered by any `Signals` its previous disposition is replaced, so e.g.
+/// SIGINT no longer terminates the process. It is restored once the last of them is dropped.
+pub struct Signals {
+    reader: PipeReader,
+    writer: PipeWriter,
+    signals: Vec<c_int>,
+}
+
+impl Signals {
+    /// Starts catching the given signals
+    pub fn new(signals: &[c_int]) -> io::Result<Signals> {
+        for &signum in signals.iter() {
+            if signum <= 0 || signum as usize >= NSIG {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid signal number"));
+            }
+        }
+
+        let (reader, writer) = try!(pipe());
+        let mut sigs = Signals {
+            reader: reader,
+            writer: writer,
+            signals: Vec::with_capacity(signals.len()),
+        };
+
+        let _guard = REGISTRY_LOCK.lock().unwrap();
+        for &signum in signals.iter() {
+            if sigs.signals.contains(&signum) {
+                continue;
+            }
+            try!(register(signum, sigs.writer.as_raw_fd()));
+            sigs.signals.push(signum);
+        }
+
+        Ok(sigs)
+    }
+
+    /// Blocks the current coroutine until one of the signals arrives, and returns its number
+    pub fn recv(&mut self) -> io::Result<c_int> {
+        let mut buf = [0u8; 1];
+        loop {
+            match self.reader.read(&mut buf) {
+                Ok(0) => return Err(io::Error::new(io::ErrorKind::Other, "signal pipe closed")),
+                Ok(..) => return Ok(buf[0] as c_int),
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Signals caught by this stream
+    pub fn signals(&self) -> &[c_int] {
+        &self.signals[..]
+    }
+}
+
+impl Iterator for Signals {
+    type Item = c_int;
+
+    fn next(&mut self) -> Option<c_int> {
+        self.recv().ok()
+    }
+}
+
+impl AsRawFd for Signals {
+    fn as_raw_fd(&self) -> RawFd {
+        self.reader.as_raw_fd()
+    }
+}
+
+impl Drop for Signals {
+    fn drop(&mut self) {
+        {
+            let _guard = REGISTRY_LOCK.lock().unwrap();
+            let fd = self.writer.as_raw_fd();
+            for &signum in self.signals.iter() {
+                unregister(signum, fd);
+            }
+        }
+
+        // A handler which started before the fd was unregistered may still write to it. They
+        // only run for a moment, and `writer` is closed after this.
+        while HANDLERS_RUNNING.load(Ordering::SeqCst) != 0 {
+            thread::yield_now();
+        }
+    }
+}
+
+// Must be called with `REGISTRY_LOCK` held
+fn register(signum: c_int, fd: RawFd) -> io::Result<()> {
+    unsafe {
+        let slots = &mut RECEIVERS[signum as usize];
+        let slot = match slots.iter().position(|&fd| fd == -1) {
+            Some(slot) => slot,
+            None => return Err(io::Error::new(io::ErrorKind::Other, "too many receivers for one signal")),
+        };
+        slots[slot] = fd;
+
+        if PREVIOUS_ACTIONS[signum as usize].is_none() {
+            match install_handler(signum) {
+                Ok(previous) => PREVIOUS_ACTIONS[signum as usize] = Some(previous),
+                Err(err) => {
+                    slots[slot] = -1;
+                    return Err(err);
+                }
+            }
+            debug!("Installed handler for signal {}", signum);
+        }
+    }
+
+    Ok(())
+}
+
+// Installs `handler` with SA_RESTART, so that interrupted system calls of other threads are
+// resumed, and returns the previous disposition
+unsafe fn install_handler(signum: c_int) -> io::Result<sys::sigaction> {
+    let mut act: sys::sigaction = mem::zeroed();
+    act.sa_sigaction = handler as usize;
+    act.sa_flags = sys::SA_RESTART;
+    let mut previous: sys::sigaction = mem::zeroed();
+    if sigaction(signum, &act, &mut previous) == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(previous)
+}
+
+// Must be called with `REGISTRY_LOCK` held
+fn unregister(signum: c_int, fd: RawFd) {
+    unsafe {
+        let slots = &mut RECEIVERS[signum as usize];
+        for slot in slots.iter_mut() {
+            if *slot == fd {
+                *slot = -1;
+            }
+        }
+
+        if slots.iter().all(|&fd| fd == -1) {
+            if let Some(previous) = PREVIOUS_ACTIONS[signum as usize].take() {
+                if sigaction(signum, &previous, 0 as *mut sys::sigaction) == -1 {
+                    warn!("Cannot restore the disposition of signal {}: {:?}", signum,
+                          io::Error::last_os_error());
+                }
+                debug!("Restored the disposition of signal {}", signum);
+            }
+        }
+    }
+}
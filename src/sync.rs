@@ -0,0 +1,60 @@
+use std::sync::{Arc, Mutex};
+use std::mem;
+
+use coroutine::coroutine::Handle;
+
+use scheduler::Scheduler;
+
+enum NotifyState {
+    Empty,
+    Waiting(Handle),
+    Notified,
+}
+
+/// A one-shot wakeup signal between coroutines
+///
+/// One coroutine calls `wait` and stays blocked until another one calls `notify`.
+#[derive(Clone)]
+pub struct Notify(Arc<Mutex<NotifyState>>);
+
+impl Notify {
+    pub fn new() -> Notify {
+        Notify(Arc::new(Mutex::new(NotifyState::Empty)))
+    }
+
+    /// Blocks the current coroutine until `notify` is called. Returns at once if it already was.
+    pub fn wait(&self) {
+        if let NotifyState::Notified = *self.0.lock().unwrap() {
+            return;
+        }
+
+        // The handle is only published once the coroutine has blocked, so `notify` can ready it
+        // right away, even from another thread
+        let state = self.0.clone();
+        debug!("Notify: Blocked current Coroutine ...");
+        Scheduler::current().block_with(move|hdl| {
+            let mut state = state.lock().unwrap();
+            match *state {
+                NotifyState::Notified => Some(hdl),
+                _ => {
+                    *state = NotifyState::Waiting(hdl);
+                    None
+                }
+            }
+        });
+        debug!("Notify: Waked up");
+    }
+
+    /// Wakes up the waiting coroutine, if any
+    pub fn notify(&self) {
+        let hdl = {
+            let mut state = self.0.lock().unwrap();
+            match mem::replace(&mut *state, NotifyState::Notified) {
+                NotifyState::Waiting(hdl) => hdl,
+                _ => return,
+            }
+        };
+
+        Scheduler::current().ready(hdl);
+    }
+}